@@ -0,0 +1,153 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{Context, Result};
+use indicatif::HumanBytes;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::catalog::short;
+use crate::config::{CACHE_DIR, DOWNLOAD_DIR, PARTIAL_DIR};
+
+/// What a cached file was downloaded from, stored next to it as `<sha256>.json`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CacheEntry {
+    pub(crate) url: String,
+    pub(crate) name: String,
+    /// Seconds since the Unix epoch
+    pub(crate) created: u64,
+}
+
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+pub fn is_sha256(value: &str) -> bool {
+    value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn entry_path(sha256: &str) -> PathBuf {
+    DOWNLOAD_DIR.join(format!("{sha256}.json"))
+}
+
+/// Deletes a cached file and its metadata
+pub fn evict(sha256: &str) {
+    let _ = fs::remove_file(DOWNLOAD_DIR.join(sha256));
+    let _ = fs::remove_file(entry_path(sha256));
+}
+
+/// The cached file with the given SHA-256, if present and intact
+pub fn get(sha256: &str) -> Option<PathBuf> {
+    let path = DOWNLOAD_DIR.join(sha256);
+    if !path.is_file() {
+        return None;
+    }
+    match sha256_file(&path) {
+        Ok(actual) if actual == sha256 => Some(path),
+        _ => {
+            eprintln!("WARNING: Cached file {} is corrupted, discarding it", path.display());
+            evict(sha256);
+            None
+        }
+    }
+}
+
+/// Cached entries with their SHA-256, newest first
+pub fn entries() -> Vec<(String, CacheEntry)> {
+    let Ok(dir) = fs::read_dir(&*DOWNLOAD_DIR) else {
+        return Vec::new();
+    };
+    let mut entries: Vec<(String, CacheEntry)> = dir
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let sha256 = name.strip_suffix(".json").filter(|sha| is_sha256(sha))?.to_string();
+            if !DOWNLOAD_DIR.join(&sha256).is_file() {
+                return None;
+            }
+            let entry = serde_json::from_str(&fs::read_to_string(entry.path()).ok()?).ok()?;
+            Some((sha256, entry))
+        })
+        .collect();
+    entries.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.created));
+    entries
+}
+
+/// The newest intact file downloaded from `url`, with its SHA-256
+pub fn find_url(url: &str) -> Option<(String, PathBuf)> {
+    let (sha256, _) = entries().into_iter().find(|(_, entry)| entry.url == url)?;
+    let path = get(&sha256)?;
+    Some((sha256, path))
+}
+
+/// Moves a finished download into the cache under its SHA-256 and returns both
+pub fn insert(file: &Path, url: &str, name: &str) -> Result<(String, PathBuf)> {
+    let sha256 = sha256_file(file)?;
+    fs::create_dir_all(&*DOWNLOAD_DIR)?;
+    let path = DOWNLOAD_DIR.join(&sha256);
+    // The directory may be shared: the same content may already be there
+    fs::rename(file, &path)
+        .with_context(|| format!("Failed to move '{}' into the download cache", file.display()))?;
+
+    let entry = CacheEntry {
+        url: url.to_string(),
+        name: name.to_string(),
+        created: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+    };
+    let tmp = DOWNLOAD_DIR.join(format!("{sha256}.json.{}.tmp", std::process::id()));
+    fs::write(&tmp, serde_json::to_string_pretty(&entry)?)?;
+    fs::rename(&tmp, entry_path(&sha256))?;
+    Ok((sha256, path))
+}
+
+/// Total size of the files below `path`
+fn dir_size(path: &Path) -> u64 {
+    let Ok(dir) = fs::read_dir(path) else {
+        return 0;
+    };
+    dir.filter_map(|entry| entry.ok())
+        .map(|entry| match entry.metadata() {
+            Ok(meta) if meta.is_dir() => dir_size(&entry.path()),
+            Ok(meta) => meta.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+pub fn list() -> Result<()> {
+    let entries = entries();
+    if entries.is_empty() {
+        println!("Download cache is empty ({})", DOWNLOAD_DIR.display());
+        return Ok(());
+    }
+    for (sha256, entry) in &entries {
+        let size = fs::metadata(DOWNLOAD_DIR.join(sha256)).map(|meta| meta.len()).unwrap_or(0);
+        println!("{}  {:>10}  {}", short(sha256), HumanBytes(size).to_string(), entry.name);
+        println!("    {}", entry.url);
+    }
+    Ok(())
+}
+
+pub fn size() -> Result<()> {
+    let downloads = dir_size(&DOWNLOAD_DIR);
+    let partial = dir_size(&PARTIAL_DIR);
+    println!("{} in {}", HumanBytes(downloads + partial), CACHE_DIR.display());
+    if partial > 0 {
+        println!("  ({} of unfinished downloads)", HumanBytes(partial));
+    }
+    Ok(())
+}
+
+/// Deletes every cached and unfinished download
+pub fn clean() -> Result<()> {
+    let freed = dir_size(&DOWNLOAD_DIR) + dir_size(&PARTIAL_DIR);
+    for dir in [&*DOWNLOAD_DIR, &*PARTIAL_DIR] {
+        if dir.exists() {
+            fs::remove_dir_all(dir).with_context(|| format!("Failed to remove '{}'", dir.display()))?;
+        }
+    }
+    println!("Removed {} from {}", HumanBytes(freed), CACHE_DIR.display());
+    Ok(())
+}
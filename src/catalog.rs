@@ -0,0 +1,164 @@
+use std::collections::HashSet;
+use std::fs;
+use anyhow::Result;
+use crate::config::{is_offline, Config, TEMPLATES_DIR};
+use crate::repository::{self, Repo, RepoSource};
+
+/// One line of `kff template list` / `kff template search`
+struct Row {
+    name: String,
+    source: String,
+    installed: String,
+    update: String,
+    description: String,
+    tags: Vec<String>,
+    maintainer: Option<String>,
+    targets: String,
+}
+
+/// Whether a template's name, description or tags contain `query`, ignoring case
+fn matches(query: &str, name: &str, description: Option<&str>, tags: &[String]) -> bool {
+    let query = query.to_lowercase();
+    name.to_lowercase().contains(&query)
+        || description.is_some_and(|description| description.to_lowercase().contains(&query))
+        || tags.iter().any(|tag| tag.to_lowercase().contains(&query))
+}
+
+pub fn short(commit: &str) -> String {
+    commit.chars().take(7).collect()
+}
+
+/// Installed commit and update status of a registry entry
+fn status(repo: &Repo) -> (String, String) {
+    let local_path = TEMPLATES_DIR.join(&repo.name);
+    let url = match &repo.url {
+        RepoSource::Remote(url) => url,
+        RepoSource::Path(_) => return ("in place".to_string(), "-".to_string()),
+        RepoSource::Local => return ("-".to_string(), "-".to_string()),
+    };
+    if !local_path.is_dir() || repository::origin_url(&local_path).is_some_and(|origin| &origin != url) {
+        return ("-".to_string(), "-".to_string());
+    }
+
+    let Some(commit) = repository::head_commit(&local_path) else {
+        return ("yes".to_string(), "-".to_string());
+    };
+    let update = if is_offline() {
+        "unknown".to_string()
+    } else {
+        match repository::remote_head(url) {
+            Ok(head) if head == commit => "up to date".to_string(),
+            Ok(head) => format!("available ({})", short(&head)),
+            Err(_) => "unknown".to_string(),
+        }
+    };
+    (short(&commit), update)
+}
+
+/// Registry entries in precedence order, followed by local templates not in any registry.
+/// With a `query`, only matching templates are listed, so the others' status is never checked.
+fn rows(query: Option<&str>) -> Result<Vec<Row>> {
+    let mut rows = Vec::new();
+    let mut installed = HashSet::new();
+
+    for registry in Config::load()?.registries() {
+        let repos = match repository::index(&registry) {
+            Ok(repos) => repos,
+            Err(e) => {
+                eprintln!("WARNING: registry '{}' is unavailable: {e:#}", registry.name);
+                continue;
+            }
+        };
+
+        let repos = repos.into_iter().filter(|repo| {
+            query.is_none_or(|query| matches(query, &repo.name, repo.description.as_deref(), &repo.tags))
+        });
+        for repo in repos {
+            let (commit, update) = status(&repo);
+            if matches!(repo.url, RepoSource::Remote(_)) && commit != "-" {
+                installed.insert(repo.name.clone());
+            }
+            rows.push(Row {
+                name: repo.name,
+                source: registry.name.clone(),
+                installed: commit,
+                update,
+                description: repo.description.unwrap_or_default(),
+                tags: repo.tags,
+                maintainer: repo.maintainer,
+                targets: repo.targets.join(","),
+            });
+        }
+    }
+
+    if TEMPLATES_DIR.is_dir() {
+        let mut local = Vec::new();
+        for entry in fs::read_dir(&*TEMPLATES_DIR)? {
+            let entry = entry?;
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if path.is_dir()
+                && !installed.contains(&name)
+                && query.is_none_or(|query| matches(query, &name, None, &[]))
+            {
+                let commit = repository::head_commit(&path).map(|c| short(&c)).unwrap_or_else(|| "yes".to_string());
+                local.push(Row {
+                    name,
+                    source: "local".to_string(),
+                    installed: commit,
+                    update: "-".to_string(),
+                    description: String::new(),
+                    tags: Vec::new(),
+                    maintainer: None,
+                    targets: String::new(),
+                });
+            }
+        }
+        local.sort_by(|a, b| a.name.cmp(&b.name));
+        rows.extend(local);
+    }
+
+    Ok(rows)
+}
+
+fn print(rows: &[Row]) {
+    if rows.is_empty() {
+        println!("No templates found");
+        return;
+    }
+
+    let width = |f: fn(&Row) -> &str, title: &str| rows.iter().map(|r| f(r).len()).max().unwrap_or(0).max(title.len());
+    let name_w = width(|r| &r.name, "NAME");
+    let source_w = width(|r| &r.source, "SOURCE");
+    let installed_w = width(|r| &r.installed, "INSTALLED");
+    let update_w = width(|r| &r.update, "UPDATE");
+    let targets_w = width(|r| &r.targets, "TARGETS");
+
+    println!(
+        "{:name_w$}  {:source_w$}  {:installed_w$}  {:update_w$}  {:targets_w$}  DESCRIPTION",
+        "NAME", "SOURCE", "INSTALLED", "UPDATE", "TARGETS"
+    );
+    for row in rows {
+        let mut description = row.description.clone();
+        if !row.tags.is_empty() {
+            description.push_str(&format!(" [{}]", row.tags.join(", ")));
+        }
+        if let Some(maintainer) = &row.maintainer {
+            description.push_str(&format!(" (by {maintainer})"));
+        }
+        println!(
+            "{:name_w$}  {:source_w$}  {:installed_w$}  {:update_w$}  {:targets_w$}  {}",
+            row.name, row.source, row.installed, row.update, row.targets, description.trim()
+        );
+    }
+}
+
+pub fn list() -> Result<()> {
+    print(&rows(None)?);
+    Ok(())
+}
+
+pub fn search(query: &str) -> Result<()> {
+    print(&rows(Some(query))?);
+    Ok(())
+}
\ No newline at end of file
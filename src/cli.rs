@@ -1,24 +1,200 @@
+use std::path::PathBuf;
 use clap::{Args, Parser, Subcommand};
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
     Generate(GenerateArgs),
+    /// Re-apply a newer template version onto a generated project
+    Update(UpdateArgs),
     Doctor,
-    Install(InstallerArgs)
+    Install(InstallerArgs),
+    /// Inspect available toolchains
+    #[command(subcommand)]
+    Toolchain(ToolchainCommands),
+    /// Tools for template authors
+    #[command(subcommand)]
+    Template(TemplateCommands),
+    /// Manage template registries
+    #[command(subcommand)]
+    Registry(RegistryCommands),
+    /// Manage the download cache
+    #[command(subcommand)]
+    Cache(CacheCommands),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CacheCommands {
+    /// List cached downloads
+    List,
+    /// Show how much disk space the cache uses
+    Size,
+    /// Delete all cached and unfinished downloads
+    Clean,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum RegistryCommands {
+    /// Add a registry or change an existing one
+    Add(RegistryAddArgs),
+    /// Remove a registry
+    Remove(RegistryNameArgs),
+    /// List registries in search order
+    List,
+    /// Refresh the cached index of one or all remote registries
+    Update(RegistryUpdateArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct RegistryUpdateArgs {
+    pub(crate) name: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct RegistryAddArgs {
+    pub(crate) name: String,
+    /// http(s) URL of a templates.json, a file:// URL, or a local index file or template directory
+    pub(crate) url: String,
+    /// Registries with a higher priority are searched first
+    #[arg(long, default_value_t = 0, allow_negative_numbers = true)]
+    pub(crate) priority: i32,
+    /// Minisign public key the index must be signed with (can be repeated)
+    #[arg(long = "key", value_name = "PUBLIC_KEY")]
+    pub(crate) keys: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct RegistryNameArgs {
+    pub(crate) name: String,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum TemplateCommands {
+    /// Check a template directory for mistakes
+    Lint(LintArgs),
+    /// List templates from all registries and local templates
+    List,
+    /// Search templates by name, description and tags
+    Search(SearchArgs),
+    /// Download a template by name, git URL or local path
+    Install(TemplateInstallArgs),
+    /// Fast-forward one or all downloaded templates
+    Update(TemplateUpdateArgs),
+    /// Delete a downloaded template
+    Remove(TemplateNameArgs),
+    /// Delete leftover staging directories
+    Prune,
+}
+
+#[derive(Args, Debug)]
+pub struct TemplateInstallArgs {
+    /// Template name (optionally `registry/name@ref`), git URL or local directory
+    pub(crate) source: String,
+    /// Branch, tag or commit to check out
+    #[arg(long = "ref", value_name = "REF")]
+    pub(crate) git_ref: Option<String>,
+    /// Replace an already installed template
+    #[arg(long)]
+    pub(crate) force: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct TemplateUpdateArgs {
+    pub(crate) name: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct TemplateNameArgs {
+    pub(crate) name: String,
+}
+
+#[derive(Args, Debug)]
+pub struct SearchArgs {
+    pub(crate) query: String,
+}
+
+#[derive(Args, Debug)]
+pub struct LintArgs {
+    pub(crate) dir: PathBuf,
 }
 
 #[derive(Args, Debug)]
 pub struct GenerateArgs {
+    /// Template name (optionally `registry/name` and/or `name@ref`), git URL, `gh:user/repo` or local directory
     pub(crate) name: String,
+    /// Branch, tag or commit of the template to use
+    #[arg(long = "ref", value_name = "REF")]
+    pub(crate) git_ref: Option<String>,
+    /// Read answers from a JSON or TOML file instead of prompting
+    #[arg(long, value_name = "FILE")]
+    pub(crate) answers: Option<PathBuf>,
+    /// Override a single answer (can be repeated)
+    #[arg(long = "set", value_name = "NAME=VALUE")]
+    pub(crate) set: Vec<String>,
+    /// Use the default value for every question that has no answer
+    #[arg(long)]
+    pub(crate) defaults: bool,
+    /// Run the template's post-generate hooks without asking
+    #[arg(long, conflicts_with = "no_hooks")]
+    pub(crate) trust: bool,
+    /// Never run the template's post-generate hooks
+    #[arg(long)]
+    pub(crate) no_hooks: bool,
+    /// Project directory to generate into (defaults to ./<app_name>)
+    #[arg(short, long, value_name = "DIR")]
+    pub(crate) output: Option<PathBuf>,
+    /// Overwrite files in an existing output directory
+    #[arg(long, conflicts_with = "merge")]
+    pub(crate) force: bool,
+    /// Only add files missing from an existing output directory
+    #[arg(long)]
+    pub(crate) merge: bool,
+    /// Show the resulting files, substitutions and diffs without writing anything
+    #[arg(long)]
+    pub(crate) dry_run: bool,
 }
+#[derive(Args, Debug)]
+pub struct UpdateArgs {
+    /// Project directory (defaults to the current directory)
+    pub(crate) dir: Option<PathBuf>,
+    /// Branch, tag or commit to update to (defaults to the template's default branch)
+    #[arg(long = "ref", value_name = "REF")]
+    pub(crate) git_ref: Option<String>,
+    /// Use the default value for new questions instead of prompting
+    #[arg(long)]
+    pub(crate) defaults: bool,
+}
+
 #[derive(Args, Debug)]
 pub struct InstallerArgs {
     pub(crate) names: Vec<String>,
+    /// Release tag of the toolchain to install (defaults to the latest release)
+    #[arg(long, value_name = "TAG")]
+    pub(crate) version: Option<String>,
+    /// Directory to install the toolchain into (defaults to `toolchain_prefix` in the config, then HOME)
+    #[arg(long, value_name = "DIR")]
+    pub(crate) prefix: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ToolchainCommands {
+    /// List the releases that provide a toolchain for a target
+    Versions(ToolchainVersionsArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct ToolchainVersionsArgs {
+    pub(crate) target: String,
 }
 
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 pub struct CliArgs {
+    /// Never touch the network; use cached registry indexes and local templates only
+    #[arg(long, global = true)]
+    pub(crate) offline: bool,
+    /// Accept unsigned registry indexes and templates that do not match their pinned commit
+    #[arg(long, global = true)]
+    pub(crate) insecure: bool,
     #[command(subcommand)]
     pub(crate) command: Commands
 }
\ No newline at end of file
@@ -1,7 +1,11 @@
 use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
 use std::path::PathBuf;
-use std::env;
+use std::{env, fs};
 use std::env::temp_dir;
+use std::sync::atomic::{AtomicBool, Ordering};
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
 
 pub const ASCII_ART: &str = r#"
 :::    :::  ::::::::::  :::::::::: 
@@ -13,16 +17,72 @@ pub const ASCII_ART: &str = r#"
 ###    ###  ###         ###       meow <3
 "#;
 
-pub static TEMPLATES_DIR: Lazy<PathBuf> = Lazy::new(|| {
-    if let Ok(dir) = env::var("KFF_TEMPLATES_DIR") {
+pub static DATA_DIR: Lazy<PathBuf> = Lazy::new(|| {
+    if let Ok(dir) = env::var("KFF_DATA_DIR") {
         PathBuf::from(dir)
     } else {
         dirs::data_dir()
             .unwrap_or_else(|| PathBuf::from("~/.local/share"))
             .join("kff")
-            .join("templates")
     }
 });
+pub static TEMPLATES_DIR: Lazy<PathBuf> = Lazy::new(|| {
+    if let Ok(dir) = env::var("KFF_TEMPLATES_DIR") {
+        PathBuf::from(dir)
+    } else {
+        DATA_DIR.join("templates")
+    }
+});
+/// Toolchains installed by kff, with their release tags
+pub static TOOLCHAINS_FILE: Lazy<PathBuf> = Lazy::new(|| DATA_DIR.join("toolchains.json"));
+/// Download cache; can be pointed at a persistent volume shared between machines
+pub static CACHE_DIR: Lazy<PathBuf> = Lazy::new(|| {
+    if let Ok(dir) = env::var("KFF_CACHE_DIR") {
+        PathBuf::from(dir)
+    } else {
+        dirs::cache_dir()
+            .unwrap_or_else(|| HOME.join(".cache"))
+            .join("kff")
+    }
+});
+/// Finished downloads, stored by their SHA-256
+pub static DOWNLOAD_DIR: Lazy<PathBuf> = Lazy::new(|| CACHE_DIR.join("downloads"));
+/// Where downloads are kept while they are in progress
+pub static PARTIAL_DIR: Lazy<PathBuf> = Lazy::new(|| CACHE_DIR.join("partial"));
+/// Base URL of the GitHub API, replaceable by a local stand-in
+pub static GITHUB_API: Lazy<String> = Lazy::new(|| {
+    env::var("KFF_GITHUB_API")
+        .map(|url| url.trim_end_matches('/').to_string())
+        .unwrap_or_else(|_| "https://api.github.com".to_string())
+});
+/// Cached copies of remote registry indexes
+pub static REGISTRY_CACHE_DIR: Lazy<PathBuf> = Lazy::new(|| DATA_DIR.join("registries"));
+
+/// Set by the global `--offline` flag; nothing may touch the network
+pub static OFFLINE: AtomicBool = AtomicBool::new(false);
+
+pub fn is_offline() -> bool {
+    OFFLINE.load(Ordering::Relaxed)
+}
+
+pub static INSECURE: AtomicBool = AtomicBool::new(false);
+
+/// Fails with `problem`, or only warns about it when `--insecure` is set
+pub fn ensure_trusted(problem: String) -> Result<()> {
+    if INSECURE.load(Ordering::Relaxed) {
+        eprintln!("WARNING: {problem} (accepted because of --insecure)");
+        return Ok(());
+    }
+    Err(anyhow!("{problem}; use --insecure to accept it anyway"))
+}
+
+/// Fails with a clear message when `--offline` is set
+pub fn ensure_online(action: &str) -> Result<()> {
+    if is_offline() {
+        return Err(anyhow!("Cannot {action} in offline mode"));
+    }
+    Ok(())
+}
 pub static KSDK: Lazy<Option<String>> = Lazy::new(|| {
     env::var("KSDK").ok()
 });
@@ -34,4 +94,87 @@ pub static HOME: Lazy<PathBuf> = Lazy::new(|| {
 pub static TEMP: Lazy<PathBuf> = Lazy::new(|| {
     temp_dir()
 });
-pub static REPOSITORY: &str = "https://raw.githubusercontent.com/progzone122/kff/main/templates.json";
\ No newline at end of file
+/// Where templates are copied and rendered before landing in a project
+pub static STAGING_DIR: Lazy<PathBuf> = Lazy::new(|| TEMP.join("kff"));
+pub static REPOSITORY: &str = "https://raw.githubusercontent.com/progzone122/kff/main/templates.json";
+pub static CONFIG_FILE: Lazy<PathBuf> = Lazy::new(|| {
+    if let Ok(file) = env::var("KFF_CONFIG") {
+        PathBuf::from(file)
+    } else {
+        dirs::config_dir()
+            .unwrap_or_else(|| HOME.join(".config"))
+            .join("kff")
+            .join("config.json")
+    }
+});
+
+/// Default time a cached registry index is used without checking the remote
+pub const DEFAULT_INDEX_TTL: u64 = 60 * 60;
+
+/// Name of the built-in registry backed by [`REPOSITORY`]
+pub const DEFAULT_REGISTRY: &str = "kff";
+
+/// User settings stored in [`CONFIG_FILE`]
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Config {
+    #[serde(default)]
+    pub(crate) registries: Vec<Registry>,
+    /// Seconds a cached registry index stays fresh
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) index_ttl: Option<u64>,
+    /// Pinned SHA-256 of toolchain archives by `target@tag`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) checksums: BTreeMap<String, String>,
+    /// Directory toolchains are installed into instead of `HOME`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) toolchain_prefix: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Registry {
+    pub(crate) name: String,
+    /// http(s) URL of an index, `file://` URL, or a local index file / template directory
+    pub(crate) url: String,
+    /// Registries with a higher priority are searched first
+    #[serde(default)]
+    pub(crate) priority: i32,
+    /// Trusted minisign public keys; when set, the index must be signed by one of them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) keys: Vec<String>,
+}
+
+impl Config {
+    pub fn load() -> Result<Self> {
+        if !CONFIG_FILE.exists() {
+            return Ok(Self::default());
+        }
+        let content = fs::read_to_string(&*CONFIG_FILE)
+            .with_context(|| format!("Failed to read {}", CONFIG_FILE.display()))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse {}", CONFIG_FILE.display()))
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if let Some(parent) = CONFIG_FILE.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&*CONFIG_FILE, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// All registries in search order: highest priority first, then by name.
+    /// The built-in registry is included unless a registry with the same name overrides it.
+    pub fn registries(&self) -> Vec<Registry> {
+        let mut registries = self.registries.clone();
+        if !registries.iter().any(|r| r.name == DEFAULT_REGISTRY) {
+            registries.push(Registry {
+                name: DEFAULT_REGISTRY.to_string(),
+                url: REPOSITORY.to_string(),
+                priority: 0,
+                keys: Vec::new(),
+            });
+        }
+        registries.sort_by(|a, b| b.priority.cmp(&a.priority).then_with(|| a.name.cmp(&b.name)));
+        registries
+    }
+}
@@ -3,10 +3,11 @@ use std::io::{BufRead, BufReader};
 use std::path::Path;
 use anyhow::{anyhow, Result};
 use crate::config::KSDK;
+use crate::installer;
 
 pub fn run() -> Result<()> {
     let lines = if let Some(ksdk_path) = KSDK.as_deref() {
-        let res: Vec<String> = read_first_lines(&ksdk_path).unwrap_or_else(|e| {
+        let res: Vec<String> = read_first_lines(ksdk_path).unwrap_or_else(|e| {
             eprintln!("Error reading file: {}", e);
             vec![]
         });
@@ -16,6 +17,7 @@ pub fn run() -> Result<()> {
     };
 
     print_ksdk(KSDK.as_deref(), lines);
+    print_toolchains();
     Ok(())
 }
 
@@ -48,7 +50,7 @@ pub fn print_ksdk(ksdk: Option<&str>, mesonfile: Vec<String>) {
     );
     print!("\nmeson-crosscompile.txt file (SDK): ");
     if !mesonfile.is_empty() {
-        println!("");
+        println!();
         for line in mesonfile {
             println!("{}", line);
         }
@@ -56,4 +58,24 @@ pub fn print_ksdk(ksdk: Option<&str>, mesonfile: Vec<String>) {
     } else {
         println!("[ERROR]");
     }
-}
\ No newline at end of file
+}
+
+pub fn print_toolchains() {
+    let toolchains = installer::installed_toolchains();
+    print!("\nToolchains (installed by kff): ");
+    if toolchains.is_empty() {
+        println!("[NONE]");
+        return;
+    }
+    println!();
+    for (target, toolchain) in toolchains {
+        match (&toolchain.sha256, &toolchain.unverified_sha256) {
+            (Some(sha256), _) => println!("{target}: {} (sha256 {sha256})", toolchain.tag),
+            (None, Some(sha256)) => println!("{target}: {} (sha256 {sha256}, NOT verified)", toolchain.tag),
+            (None, None) => println!("{target}: {}", toolchain.tag),
+        }
+        if let Some(prefix) = &toolchain.prefix {
+            println!("  installed in {}", prefix.display());
+        }
+    }
+}
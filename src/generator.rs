@@ -0,0 +1,476 @@
+use std::fs;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::process;
+use anyhow::{anyhow, Context};
+use fs_extra::dir::{copy, CopyOptions};
+use fs_extra::{dir, file};
+use serde_json::Value;
+use crate::cli::GenerateArgs;
+use crate::config::{self, STAGING_DIR, TEMPLATES_DIR};
+use crate::repository::{self, Repo, RepoSource};
+use crate::lock::{BaseLock, TemplateLock};
+use crate::template::{Answers, RenderReport, Template};
+use crate::{hooks, template};
+
+fn read_definition(dir: &Path) -> anyhow::Result<Value> {
+    let path = dir.join("template.json");
+    let data = fs::read_to_string(&path).with_context(|| format!("Failed to read '{}'", path.display()))?;
+    serde_json::from_str(&data).with_context(|| format!("Invalid template definition '{}'", path.display()))
+}
+
+/// Which versions of the templates a template `extends` get staged
+pub enum Bases<'a> {
+    /// The downloaded ones, as they are
+    Installed,
+    /// The newest commit of their default branch (or registry ref), when online
+    Latest,
+    /// The versions recorded in a lock file
+    Locked(&'a [BaseLock]),
+}
+
+/// Copies a template into a fresh staging directory. Templates it `extends` are
+/// copied first, so its own files override theirs, and the merged definition
+/// replaces its template.json. Returns the bases used, for the lock file.
+pub fn stage_files(template_dir: &Path, staging: &Path, bases: Bases) -> anyhow::Result<Vec<BaseLock>> {
+    if staging.exists() {
+        fs::remove_dir_all(staging)?;
+    }
+
+    let mut layers = vec![template_dir.to_path_buf()];
+    let mut used = Vec::new();
+    while let Some(base) = read_definition(layers.last().unwrap())?.get("extends").and_then(Value::as_str) {
+        println!("Extending template '{base}'");
+        let from = layers.last().unwrap();
+        let (base_dir, resolved) = match &bases {
+            // Relative bases come with the extending template's own checkout
+            Bases::Locked(locked) if !repository::is_relative_base(base) && used.len() < locked.len() => {
+                (repository::checkout_base(&locked[used.len()])?, locked[used.len()].clone())
+            }
+            Bases::Latest => repository::resolve_base(base, from, true)?,
+            _ => repository::resolve_base(base, from, false)?,
+        };
+        let canonical = base_dir.canonicalize()?;
+        if layers.iter().any(|layer| layer.canonicalize().is_ok_and(|layer| layer == canonical)) {
+            return Err(anyhow!("Templates extend each other in a cycle through '{base}'"));
+        }
+        layers.push(base_dir);
+        used.push(resolved);
+    }
+
+    let mut definition = Value::Null;
+    for (i, layer) in layers.iter().rev().enumerate() {
+        let mut options = CopyOptions::new();
+        options.overwrite = true;
+        if i == 0 {
+            options.copy_inside = true;
+        } else {
+            options.content_only = true;
+        }
+        copy(layer, staging, &options)?;
+        definition = template::merge_definitions(definition, read_definition(layer)?);
+    }
+
+    if layers.len() > 1 {
+        fs::write(staging.join("template.json"), serde_json::to_string_pretty(&definition)?)?;
+    }
+    Ok(used)
+}
+
+/// Stages a template with [`stage_files`] and parses its template.json
+pub fn stage(template_dir: &Path, staging: &Path, bases: Bases) -> anyhow::Result<(Template, Vec<BaseLock>)> {
+    let bases = stage_files(template_dir, staging, bases)?;
+    Ok((Template::parse_from_file(staging.join("template.json"))?, bases))
+}
+
+/// Renders a staged template in place and removes what only belongs to the template
+pub fn render_staged(template: &Template, answers: &Answers, staging: &Path) -> anyhow::Result<RenderReport> {
+    let report = template.render(answers, staging)?;
+
+    // Unnecessary files/dirs need to be deleted
+    file::remove(staging.join("template.json"))?;
+    dir::remove(staging.join(".git"))?;
+    Ok(report)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum OutputMode {
+    /// The output directory must not exist yet (or be empty)
+    New,
+    /// Existing files are overwritten, other files are left alone
+    Force,
+    /// Existing files are kept, only missing ones are added
+    Merge,
+}
+
+fn is_empty_or_missing(path: &Path) -> anyhow::Result<bool> {
+    if !path.exists() {
+        return Ok(true);
+    }
+    Ok(path.is_dir() && fs::read_dir(path)?.next().is_none())
+}
+
+/// Copies the rendered project in `src` into `dest`, returning (written, skipped) file counts
+fn write_output(src: &Path, dest: &Path, mode: OutputMode) -> anyhow::Result<(usize, usize)> {
+    if dest.exists() && !dest.is_dir() {
+        return Err(anyhow!("'{}' exists and is not a directory", dest.display()));
+    }
+    fs::create_dir_all(dest)?;
+
+    let (mut written, mut skipped) = (0, 0);
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let path = entry.path();
+        let target = dest.join(entry.file_name());
+
+        if path.is_dir() {
+            let (w, s) = write_output(&path, &target, mode)?;
+            written += w;
+            skipped += s;
+        } else if target.is_dir() {
+            return Err(anyhow!("'{}' exists and is a directory", target.display()));
+        } else if target.exists() && mode == OutputMode::Merge {
+            skipped += 1;
+        } else {
+            fs::copy(&path, &target)
+                .with_context(|| format!("Failed to write '{}'", target.display()))?;
+            written += 1;
+        }
+    }
+    Ok((written, skipped))
+}
+
+/// Prints what a generation would produce: the file tree, substitutions and diffs
+fn print_dry_run(report: &RenderReport, staged: &Path, out_path: &Path, mode: OutputMode) -> anyhow::Result<()> {
+    println!("\nFiles ({}):", out_path.display());
+    let mut changed = Vec::new();
+    print_tree(staged, staged, out_path, mode, 1, &mut changed)?;
+
+    if !report.substitutions.is_empty() {
+        println!("\nPlaceholders:");
+        for sub in &report.substitutions {
+            match (&sub.value, sub.count) {
+                (None, _) => println!("  {}: '{}' has no answer, left as is", sub.file, sub.placeholder),
+                (Some(_), 0) => println!("  {}: '{}' matched nothing", sub.file, sub.placeholder),
+                (Some(value), count) => println!("  {}: '{}' -> '{}' ({} time(s))", sub.file, sub.placeholder, value, count),
+            }
+        }
+    }
+    if !report.rendered.is_empty() {
+        println!("\nRendered templates:");
+        for name in &report.rendered {
+            println!("  {name}");
+        }
+    }
+
+    for relative in changed {
+        let old = fs::read(out_path.join(&relative))?;
+        let new = fs::read(staged.join(&relative))?;
+        println!("\nDiff for {}:", relative.display());
+        match (String::from_utf8(old), String::from_utf8(new)) {
+            (Ok(old), Ok(new)) => print!("{}", diffy::create_patch(&old, &new)),
+            _ => println!("Binary file differs"),
+        }
+    }
+    Ok(())
+}
+
+fn print_tree(
+    root: &Path,
+    dir: &Path,
+    out_path: &Path,
+    mode: OutputMode,
+    depth: usize,
+    changed: &mut Vec<PathBuf>,
+) -> anyhow::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let indent = "  ".repeat(depth);
+        let name = entry.file_name().to_string_lossy().into_owned();
+
+        if path.is_dir() {
+            println!("{indent}{name}/");
+            print_tree(root, &path, out_path, mode, depth + 1, changed)?;
+            continue;
+        }
+
+        let relative = path.strip_prefix(root)?.to_path_buf();
+        let target = out_path.join(&relative);
+        let status = if !target.exists() {
+            "new"
+        } else if mode == OutputMode::Merge {
+            "kept"
+        } else if fs::read(&target)? == fs::read(&path)? {
+            "unchanged"
+        } else {
+            changed.push(relative);
+            "changed"
+        };
+        println!("{indent}{name}  [{status}]");
+    }
+    Ok(())
+}
+
+pub fn run(generate_args: &GenerateArgs) -> anyhow::Result<()> {
+    println!("Kindle Fucking Forge generate started...");
+    println!("{}", config::ASCII_ART);
+    let (name, name_ref) = repository::split_ref(&generate_args.name);
+    // A git URL or a local directory is used directly, without a registry or TEMPLATES_DIR
+    let source = match repository::from_source(name) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("ERROR: {e:#}");
+            process::exit(1);
+        }
+    };
+    let adhoc = source.is_some();
+    let repo = match source {
+        Some(repo) => repo,
+        None => {
+            let (_, template_name) = repository::split_name(name);
+            println!("Searching for the {} template in the kff registries...", name);
+            match repository::search(name) {
+                Ok(repo) => repo,
+                Err(e) => {
+                    // Couldn't get the remote repo -> try the local
+                    let local_path = TEMPLATES_DIR.join(template_name);
+                    if local_path.exists() && local_path.is_dir() {
+                        eprintln!("WARNING: {e:#}; falling back to the local copy");
+                        Repo::new(template_name, RepoSource::Local)
+                    } else {
+                        eprintln!("ERROR: Template '{}' not found remotely and no local copy available: {e:#}", generate_args.name);
+                        process::exit(1);
+                    }
+                }
+            }
+        }
+    };
+    let template_name = repo.name.clone();
+
+    // Never prompt in CI or when answers come from elsewhere
+    let interactive = generate_args.answers.is_none()
+        && !generate_args.defaults
+        && std::io::stdin().is_terminal();
+
+    // --ref beats name@ref, which beats the registry's pin and then its ref
+    let chosen_ref = generate_args.git_ref.as_deref().or(name_ref);
+    // A template used in place cannot be checked out, only checked against its pin
+    let git_ref = match &repo.url {
+        RepoSource::Path(_) => chosen_ref.or(repo.git_ref.as_deref()),
+        _ => chosen_ref.or(repo.default_ref()),
+    };
+
+    let mut tdir: PathBuf = if let RepoSource::Path(path) = &repo.url {
+        if let Some(git_ref) = git_ref {
+            eprintln!("ERROR: Cannot check out '{git_ref}': template '{}' is used in place from {}", repo.name, path.display());
+            process::exit(1);
+        }
+        println!("Using template '{}' from {}", repo.name, path.display());
+        path.clone()
+    } else if let (true, RepoSource::Remote(url)) = (adhoc, &repo.url) {
+        let checkout = STAGING_DIR.join("sources").join(&repo.name);
+        println!("Cloning template '{}' from {url}", repo.name);
+        if let Err(e) = repository::clone_into(url, &checkout) {
+            eprintln!("ERROR: {e:#}");
+            process::exit(1);
+        }
+        checkout
+    } else {
+        let local_path = TEMPLATES_DIR.join(&repo.name);
+        let use_local = match &repo.url {
+            RepoSource::Remote(_) if local_path.is_dir() && config::is_offline() => true,
+            // The requested ref is checked out in a copy of the existing one
+            RepoSource::Remote(url) if local_path.is_dir() && git_ref.is_some() => {
+                repository::origin_url(&local_path).is_some_and(|origin| &origin == url)
+            }
+            RepoSource::Remote(url) if local_path.is_dir() => {
+                if repository::origin_url(&local_path).is_some_and(|origin| &origin != url) {
+                    println!("Local template '{}' was downloaded from another source", repo.name);
+                    false
+                } else {
+                    repository::choose_local_or_download(&Repo::new(&repo.name, RepoSource::Local), interactive)?
+                }
+            }
+            RepoSource::Remote(_) => false,
+            _ => true,
+        };
+
+        if use_local {
+            println!("Using local template '{}'", repo.name);
+        } else {
+            println!("Downloading template '{}'", repo.name);
+            if let Err(e) = repository::download(&repo, chosen_ref) {
+                eprintln!("ERROR: {e}");
+                process::exit(1);
+            }
+        }
+        local_path
+    };
+
+    let commit = match git_ref {
+        Some(git_ref) => {
+            // Ad-hoc sources are private clones; a downloaded template stays on its branch
+            // and the ref is checked out in a copy of it
+            let checked_out = if adhoc {
+                repository::checkout_ref(&tdir, git_ref)
+            } else {
+                let checkout = STAGING_DIR.join("checkouts").join(&repo.name);
+                let commit = repository::checkout_copy(&tdir, git_ref, &checkout);
+                tdir = checkout;
+                commit
+            };
+            match checked_out {
+                Ok(commit) => {
+                    println!("Checked out '{git_ref}' ({commit})");
+                    Some(commit)
+                }
+                Err(e) => {
+                    eprintln!("ERROR: {e:#}");
+                    process::exit(1);
+                }
+            }
+        }
+        None => repository::head_commit(&tdir),
+    };
+
+    // The registry's pin covers the version it offers, not one picked with --ref or name@ref
+    if chosen_ref.is_none()
+        && let Err(e) = repository::verify_pin(&repo, commit.as_deref())
+    {
+        eprintln!("ERROR: {e:#}");
+        process::exit(1);
+    }
+
+    println!("Parsing the template.json file...");
+
+    // tmp dir
+    let tmp_template_path = STAGING_DIR.join("render").join(&repo.name);
+
+    let mut provided = match &generate_args.answers {
+        Some(path) => template::load_answers(path)?,
+        None => template::Answers::new(),
+    };
+    for arg in &generate_args.set {
+        let (name, value) = template::parse_set(arg)?;
+        provided.insert(name, value);
+    }
+
+    let mut lock = TemplateLock {
+        template: repo.name.clone(),
+        registry: repo.registry.clone(),
+        url: match &repo.url {
+            RepoSource::Remote(url) => Some(url.clone()),
+            RepoSource::Path(path) => Some(path.to_string_lossy().into_owned()),
+            RepoSource::Local => repository::origin_url(&tdir),
+        },
+        // The pin is checked against the registry again on update, so only a ref is recorded
+        git_ref: chosen_ref.or(repo.git_ref.as_deref()).map(str::to_string),
+        commit,
+        subdir: repo.subdir.clone(),
+        bases: Vec::new(),
+        answers: Default::default(),
+    };
+
+    // Only the template's own subtree of a repository holding several templates is copied
+    let template_dir = match repository::template_dir(&tdir, repo.subdir.as_deref()) {
+        Ok(dir) => dir,
+        Err(e) => {
+            eprintln!("ERROR: {e}");
+            process::exit(1);
+        }
+    };
+    let repo = match stage(&template_dir, &tmp_template_path, Bases::Installed) {
+        Ok((repo, bases)) => {
+            lock.bases = bases;
+            repo
+        }
+        Err(e) => {
+            eprintln!("ERROR: {e}");
+            process::exit(1);
+        }
+    };
+
+    println!("Starting the '{}' template generator", template_name);
+    let answers = match repo.collect_answers(&provided, generate_args.defaults, interactive) {
+        Ok(answers) => answers,
+        Err(e) => {
+            eprintln!("ERROR: {e}");
+            process::exit(1);
+        }
+    };
+    let report = render_staged(&repo, &answers, &tmp_template_path)?;
+
+    let out_path = match &generate_args.output {
+        Some(output) => output.clone(),
+        None => {
+            let app_name = answers
+                .get(repo.output_key())
+                .map(template::value_to_string)
+                .unwrap_or_else(|| template_name.to_string());
+            std::env::current_dir()?.join(app_name)
+        }
+    };
+
+    let mode = if generate_args.force {
+        OutputMode::Force
+    } else if generate_args.merge {
+        OutputMode::Merge
+    } else {
+        OutputMode::New
+    };
+    let refused = mode == OutputMode::New && !is_empty_or_missing(&out_path)?;
+
+    if generate_args.dry_run {
+        if refused {
+            println!("WARNING: '{}' already exists; generation would be refused without --force or --merge", out_path.display());
+        }
+        print_dry_run(&report, &tmp_template_path, &out_path, mode)?;
+
+        let post_generate = repo.hooks(&answers)?;
+        if !post_generate.is_empty() {
+            println!("\nPost-generate hooks (not run):");
+            for hook in &post_generate {
+                println!("  {} {}", hook.command, hook.args.join(" "));
+            }
+        }
+        println!("\nDry run: nothing was written to {}", out_path.display());
+        return Ok(());
+    }
+
+    if refused {
+        eprintln!(
+            "ERROR: '{}' already exists. Use --force to overwrite its files, --merge to only add missing ones, or --output to pick another directory",
+            out_path.display()
+        );
+        process::exit(1);
+    }
+
+    let (written, skipped) = write_output(&tmp_template_path, &out_path, mode)?;
+    if skipped > 0 {
+        println!("{written} file(s) written, {skipped} existing file(s) kept");
+    }
+
+    // Merging only adds missing files, so an existing lock keeps describing the files that were there
+    if mode == OutputMode::Merge && TemplateLock::path(&out_path).exists() {
+        println!("Kept the existing {}", TemplateLock::path(&out_path).display());
+    } else {
+        lock.answers = answers.clone().into_iter().collect();
+        lock.write(&out_path)?;
+    }
+
+    println!("Project generated at: {}", out_path.display());
+
+    let post_generate = repo.hooks(&answers)?;
+    if generate_args.no_hooks {
+        if !post_generate.is_empty() {
+            println!("Skipping {} post-generate hook(s)", post_generate.len());
+        }
+    } else {
+        hooks::run(&post_generate, &out_path, generate_args.trust)?;
+    }
+
+    Ok(())
+}
\ No newline at end of file
@@ -0,0 +1,86 @@
+use std::io;
+use std::io::{IsTerminal, Write};
+use std::path::{Component, Path};
+use std::process::Command;
+use anyhow::{anyhow, Result};
+use crate::installer::run_command;
+use crate::template::Hook;
+
+fn describe(hook: &Hook) -> String {
+    let mut line = hook.command.clone();
+    for arg in &hook.args {
+        line.push(' ');
+        if arg.is_empty() || arg.contains(char::is_whitespace) {
+            line.push_str(&format!("{arg:?}"));
+        } else {
+            line.push_str(arg);
+        }
+    }
+    if let Some(cwd) = &hook.cwd {
+        line.push_str(&format!("  (in {cwd})"));
+    }
+    line
+}
+
+fn confirm(trust: bool) -> Result<bool> {
+    if trust {
+        return Ok(true);
+    }
+    if !io::stdin().is_terminal() {
+        println!("Not running hooks without a terminal to confirm them; use --trust to run them anyway.");
+        return Ok(false);
+    }
+
+    loop {
+        println!("Run these commands? (y/n)");
+        print!("> ");
+        io::stdout().flush()?;
+
+        let mut input = String::new();
+        io::stdin().read_line(&mut input)?;
+        let input = input.trim().to_lowercase();
+
+        match input.as_str() {
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => println!("Please enter 'y' or 'n'."),
+        }
+    }
+}
+
+/// Shows the post-generate hooks and runs them in `project_dir` once the user agrees
+pub fn run(hooks: &[Hook], project_dir: &Path, trust: bool) -> Result<()> {
+    if hooks.is_empty() {
+        return Ok(());
+    }
+
+    println!("The template wants to run the following commands in {}:", project_dir.display());
+    for (i, hook) in hooks.iter().enumerate() {
+        println!("  {}. {}", i + 1, describe(hook));
+    }
+
+    if !confirm(trust)? {
+        println!("Hooks skipped.");
+        return Ok(());
+    }
+
+    for hook in hooks {
+        let cwd = match &hook.cwd {
+            Some(cwd) => {
+                let relative = Path::new(cwd);
+                if relative.components().any(|c| !matches!(c, Component::Normal(_) | Component::CurDir)) {
+                    return Err(anyhow!("Hook directory '{cwd}' must stay inside the project"));
+                }
+                project_dir.join(relative)
+            }
+            None => project_dir.to_path_buf(),
+        };
+
+        run_command(
+            Command::new(&hook.command).args(&hook.args).current_dir(&cwd),
+            &describe(hook),
+        )?;
+    }
+
+    Ok(())
+}
\ No newline at end of file
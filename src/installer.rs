@@ -1,21 +1,35 @@
-use std::io::{BufRead, Read};
-use std::io::{BufReader, Cursor};
-use std::path::Path;
-use std::process::{Command, Stdio};
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::{self, Command, Stdio};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use flate2::read::GzDecoder;
-use tar::Archive;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use tar::{Archive, Builder};
 use reqwest::blocking::Client;
-use serde::Deserialize;
+use reqwest::header::RANGE;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
 use anyhow::{anyhow, Context, Result};
 use fs_extra::dir;
 use git2::{FetchOptions, Progress, RemoteCallbacks, Repository, SubmoduleUpdateOptions};
 use indicatif::{ProgressBar, ProgressStyle};
-use crate::config::{HOME, TEMP, TEMPLATES_DIR};
-
-
-fn run_command(cmd: &mut Command, desc: &str) -> Result<()> {
+use sha2::{Digest, Sha256};
+use crate::cache;
+use crate::config::{ensure_online, ensure_trusted, Config, GITHUB_API, HOME, PARTIAL_DIR, TEMP, TOOLCHAINS_FILE};
+use crate::repository;
+
+const TOOLCHAIN_REPO: &str = "koreader/koxtoolchain";
+/// Extra attempts after a failed download
+const DOWNLOAD_RETRIES: u32 = 4;
+/// How long a download may go without receiving data before the attempt is given up
+const STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub fn run_command(cmd: &mut Command, desc: &str) -> Result<()> {
     println!("Running {desc}...");
 
     let mut child = cmd
@@ -32,13 +46,13 @@ fn run_command(cmd: &mut Command, desc: &str) -> Result<()> {
 
     // Параллельно читаем stdout и stderr
     let stdout_thread = std::thread::spawn(move || {
-        for line in stdout_reader.lines().flatten() {
+        for line in stdout_reader.lines().map_while(Result::ok) {
             println!("{}", line);
         }
     });
 
     let stderr_thread = std::thread::spawn(move || {
-        for line in stderr_reader.lines().flatten() {
+        for line in stderr_reader.lines().map_while(Result::ok) {
             eprintln!("{}", line);
         }
     });
@@ -141,31 +155,141 @@ pub fn clone_with_progress<P: AsRef<Path>>(url: &str, dest: P) -> Result<Reposit
 }
 
 
-pub fn toolchain(target: &str) -> Result<()> {
-    let repo: &str = "koreader/koxtoolchain";
-    let version: &str = "latest";
+/// A toolchain installed by kff, recorded for `kff doctor`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InstalledToolchain {
+    /// Release tag of koxtoolchain
+    pub(crate) tag: String,
+    pub(crate) url: String,
+    /// Verified SHA-256 of the archive
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) sha256: Option<String>,
+    /// SHA-256 of an archive installed with `--insecure` because none was published or pinned
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) unverified_sha256: Option<String>,
+    /// Directory the archive was unpacked into; older records mean `HOME`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) prefix: Option<PathBuf>,
+}
+
+/// Installed toolchains by target
+pub fn installed_toolchains() -> BTreeMap<String, InstalledToolchain> {
+    fs::read_to_string(&*TOOLCHAINS_FILE)
+        .ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default()
+}
+
+fn record_toolchain(target: &str, toolchain: InstalledToolchain) -> Result<()> {
+    let mut installed = installed_toolchains();
+    installed.insert(target.to_string(), toolchain);
+    if let Some(parent) = TOOLCHAINS_FILE.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&*TOOLCHAINS_FILE, serde_json::to_string_pretty(&installed)?)?;
+    Ok(())
+}
+
+/// Installs the toolchain for `target`, which may carry a release tag as `target@tag`
+pub fn toolchain(target: &str, version: Option<&str>, prefix: Option<&Path>) -> Result<()> {
+    ensure_online("install the toolchain")?;
+    let prefix = match prefix {
+        Some(prefix) => std::path::absolute(prefix)?,
+        None => match Config::load()?.toolchain_prefix {
+            Some(prefix) => repository::absolute_path(&prefix)?,
+            None => HOME.clone(),
+        },
+    };
+    let (target, target_version) = match target.split_once('@') {
+        Some((target, version)) => (target, Some(version)),
+        None => (target, None),
+    };
+    let version = version.or(target_version).unwrap_or("latest");
     let asset_name: &str = &format!("{target}.tar.gz");
 
-    let url = get_release_download_url(repo, version, asset_name)?;
-    println!("Downloading from: {}", url);
+    let release = get_release(TOOLCHAIN_REPO, version)?;
+    let asset = release.asset(asset_name)?;
+    let url = asset.browser_download_url.clone();
+    println!("Installing the {target} toolchain {} into {}", release.tag_name, prefix.display());
+
+    let expected = expected_sha256(&release, asset, target)?;
+    match &expected {
+        Some((sha256, source)) => println!("Expected SHA-256 ({source}): {sha256}"),
+        None => ensure_trusted(format!("No SHA-256 is published or pinned for {asset_name} in release '{}'", release.tag_name))?,
+    }
+
+    let sha256 = download_and_extract(&url, &prefix, expected.as_ref().map(|(sha256, _)| sha256.as_str()))?;
+
+    let (sha256, unverified_sha256) = match expected {
+        Some(_) => (Some(sha256), None),
+        None => (None, Some(sha256)),
+    };
+    let toolchain = InstalledToolchain { tag: release.tag_name, url, sha256, unverified_sha256, prefix: Some(prefix) };
+    record_toolchain(target, toolchain)?;
+    Ok(())
+}
+
+/// Lists the koxtoolchain releases that have an archive for `target`
+pub fn toolchain_versions(target: &str) -> Result<()> {
+    ensure_online("list toolchain versions")?;
+    let asset_name = format!("{target}.tar.gz");
+    let installed = installed_toolchains().remove(target).map(|toolchain| toolchain.tag);
+
+    let releases: Vec<Release> = get_releases(TOOLCHAIN_REPO)?
+        .into_iter()
+        .filter(|release| release.asset(&asset_name).is_ok())
+        .collect();
+    if releases.is_empty() {
+        println!("No releases provide a toolchain for '{target}'");
+        return Ok(());
+    }
 
-    let path_string: String = HOME.to_string_lossy().into_owned();
-    download_and_extract(&url, &path_string)?;
+    for release in releases {
+        let date = release.published_at.as_deref().and_then(|date| date.get(..10)).unwrap_or("");
+        let mark = if installed.as_deref() == Some(release.tag_name.as_str()) { "  (installed)" } else { "" };
+        println!("{:<16} {date}{mark}", release.tag_name);
+    }
+    Ok(())
+}
 
+/// Packs a fresh SDK checkout into the download cache under `key` (`url#commit`)
+fn cache_checkout(checkout: &Path, key: &str) -> Result<()> {
+    fs::create_dir_all(&*PARTIAL_DIR)?;
+    let packed = PARTIAL_DIR.join(format!("kindle-sdk-{}.tar.gz", process::id()));
+    let mut builder = Builder::new(GzEncoder::new(File::create(&packed)?, Compression::default()));
+    builder.follow_symlinks(false);
+    builder.append_dir_all(".", checkout)?;
+    builder.into_inner()?.finish()?;
+    cache::insert(&packed, key, "kindle-sdk.tar.gz")?;
     Ok(())
 }
 
 pub fn sdk(target: &str) -> Result<()> {
+    ensure_online("install the SDK")?;
     let destination_path = TEMP.join("kindle-sdk");
     let url: &str = "https://github.com/KindleModding/kindle-sdk.git";
 
-    println!("Downloading SDK...");
-
     dir::create(&destination_path, true)?;
 
-    clone_with_progress(&url, &destination_path)?;
+    // Checkouts are cached by commit, so an unchanged SDK is not cloned again
+    let key = repository::remote_head(url).ok().map(|commit| format!("{url}#{commit}"));
+    match key.as_deref().and_then(cache::find_url) {
+        Some((_, packed)) => {
+            println!("Using cached SDK checkout {}", packed.display());
+            Archive::new(GzDecoder::new(BufReader::new(File::open(&packed)?))).unpack(&destination_path)?;
+        }
+        None => {
+            println!("Downloading SDK...");
+            clone_with_progress(url, &destination_path)?;
+            if let Some(key) = &key
+                && let Err(e) = cache_checkout(&destination_path, key)
+            {
+                eprintln!("WARNING: Failed to cache the SDK checkout: {e:#}");
+            }
+        }
+    }
 
-    println!("Cloned 'kindle-sdk' into {:?}", destination_path);
+    println!("Prepared 'kindle-sdk' in {:?}", destination_path);
 
     let script_path = destination_path.join("gen-sdk.sh");
 
@@ -181,72 +305,697 @@ pub fn sdk(target: &str) -> Result<()> {
 struct ReleaseAsset {
     name: String,
     browser_download_url: String,
+    /// `sha256:<hex>`, published by GitHub for newer uploads
+    #[serde(default)]
+    digest: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 struct Release {
     tag_name: String,
+    #[serde(default)]
+    published_at: Option<String>,
     assets: Vec<ReleaseAsset>,
 }
 
-fn get_release_download_url(repo: &str, tag: &str, target_filename: &str) -> Result<String> {
+impl Release {
+    fn asset(&self, target_filename: &str) -> Result<&ReleaseAsset> {
+        self.assets
+            .iter()
+            .find(|asset| asset.name.ends_with(target_filename))
+            .ok_or_else(|| anyhow!("No asset named '{}' found in release '{}'", target_filename, self.tag_name))
+    }
+}
+
+/// Finds the SHA-256 an archive must have, and where it comes from: a value pinned in the
+/// kff config, the digest in the release API, or a checksum file attached to the release
+fn expected_sha256(release: &Release, asset: &ReleaseAsset, target: &str) -> Result<Option<(String, &'static str)>> {
+    let key = format!("{target}@{}", release.tag_name);
+    if let Some(pinned) = Config::load()?.checksums.get(&key) {
+        if !cache::is_sha256(pinned) {
+            return Err(anyhow!("Pinned checksum for '{key}' is not a SHA-256: {pinned}"));
+        }
+        return Ok(Some((pinned.to_lowercase(), "pinned in the kff config")));
+    }
+
+    if let Some(digest) = asset.digest.as_deref().and_then(|digest| digest.strip_prefix("sha256:"))
+        && cache::is_sha256(digest)
+    {
+        return Ok(Some((digest.to_lowercase(), "release API digest")));
+    }
+
+    // `<asset>.sha256` or a SHA256SUMS-style list of `<hash>  <file>` lines
+    let own = format!("{}.sha256", asset.name);
+    let checksum_assets = release.assets.iter().filter(|candidate| {
+        let name = candidate.name.to_lowercase();
+        candidate.name == own || (!name.ends_with(".sha256") && (name.contains("sha256") || name.contains("checksums")))
+    });
+    let client = Client::builder().user_agent("kff").build()?;
+    for checksum_asset in checksum_assets {
+        let text = client
+            .get(&checksum_asset.browser_download_url)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.text())
+            .with_context(|| format!("Failed to fetch {}", checksum_asset.name))?;
+        for line in text.lines() {
+            let mut fields = line.split_whitespace();
+            let (Some(hash), file) = (fields.next(), fields.next()) else {
+                continue;
+            };
+            let file = file.map(|file| file.trim_start_matches('*'));
+            if cache::is_sha256(hash) && (checksum_asset.name == own || file == Some(asset.name.as_str())) {
+                return Ok(Some((hash.to_lowercase(), "release checksum file")));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+fn get_release(repo: &str, tag: &str) -> Result<Release> {
     let url = if tag == "latest" {
-        format!("https://api.github.com/repos/{}/releases/latest", repo)
+        format!("{}/repos/{}/releases/latest", *GITHUB_API, repo)
     } else {
-        format!("https://api.github.com/repos/{}/releases/tags/{}", repo, tag)
+        format!("{}/repos/{}/releases/tags/{}", *GITHUB_API, repo, tag)
     };
 
     let client = Client::builder().user_agent("kff").build()?;
-    let release: Release = client.get(&url).send()?.json()?;
+    let response = client.get(&url).send().with_context(|| format!("Failed to fetch release '{tag}' of {repo}"))?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Err(anyhow!("Release '{}' not found in {}", tag, repo));
+    }
+    Ok(response.error_for_status()?.json()?)
+}
+
+fn get_releases(repo: &str) -> Result<Vec<Release>> {
+    let url = format!("{}/repos/{}/releases?per_page=100", *GITHUB_API, repo);
+    let client = Client::builder().user_agent("kff").build()?;
+    let response = client.get(&url).send().with_context(|| format!("Failed to fetch the releases of {repo}"))?;
+    Ok(response.error_for_status()?.json()?)
+}
 
-    for asset in release.assets {
-        if asset.name.ends_with(target_filename) {
-            return Ok(asset.browser_download_url);
+/// Downloads `url` to `dest`. Data goes to `dest.part` first, so an interrupted download
+/// is resumed with an HTTP Range request; failures are retried with exponential backoff.
+fn download(url: &str, dest: &Path) -> Result<()> {
+    // The blocking client applies the timeout to waiting for the response and to every
+    // read of the body, not to the whole transfer: a stalled connection fails the attempt
+    // and the next one resumes, however long the download takes
+    let client = Client::builder()
+        .user_agent("kff")
+        .connect_timeout(Duration::from_secs(30))
+        .timeout(STALL_TIMEOUT)
+        .build()?;
+    download_with(&client, url, dest, Duration::from_secs(1))
+}
+
+/// [`download`] with a given client; the delay before a retry starts at twice `retry_delay`
+/// and doubles on every attempt
+fn download_with(client: &Client, url: &str, dest: &Path, retry_delay: Duration) -> Result<()> {
+    let mut part = dest.as_os_str().to_owned();
+    part.push(".part");
+    let part = PathBuf::from(part);
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut attempt = 0;
+    loop {
+        match download_attempt(client, url, &part) {
+            Ok(()) => break,
+            Err(e) => {
+                // A missing file or a refused request will not get better by retrying
+                let client_error = e
+                    .downcast_ref::<reqwest::Error>()
+                    .and_then(reqwest::Error::status)
+                    .is_some_and(|status| status.is_client_error());
+                if client_error || attempt >= DOWNLOAD_RETRIES {
+                    return Err(e.context(format!("Download of {url} failed")));
+                }
+                attempt += 1;
+                let delay = retry_delay * (1 << attempt);
+                eprintln!("WARNING: {e:#}; retrying in {:.0?} ({attempt}/{DOWNLOAD_RETRIES})", delay);
+                std::thread::sleep(delay);
+            }
         }
     }
 
-    Err(anyhow!("No asset named '{}' found in release '{}'", target_filename, tag).into())
+    fs::rename(&part, dest)?;
+    Ok(())
 }
 
-fn download_and_extract(url: &str, out_dir: &str) -> Result<()> {
-    let response = reqwest::blocking::get(url)?;
-    if !response.status().is_success() {
-        return Err(anyhow!("Download failed: {}", response.status()).into());
+/// One request, appending to `part` if the server honours the Range header
+fn download_attempt(client: &Client, url: &str, part: &Path) -> Result<()> {
+    let offset = fs::metadata(part).map(|meta| meta.len()).unwrap_or(0);
+    let mut request = client.get(url);
+    if offset > 0 {
+        request = request.header(RANGE, format!("bytes={offset}-"));
     }
 
-    let total_size = response
-        .content_length()
-        .ok_or_else(|| anyhow!("Failed to get content length"))?;
+    let response = request.send()?;
+    if response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        // The partial file does not belong to what the server has now
+        fs::remove_file(part)?;
+        return Err(anyhow!("Server rejected resuming the download, starting over"));
+    }
+    let response = response.error_for_status()?;
 
-    let pb = ProgressBar::new(total_size);
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .template("{msg} [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")?
-            .progress_chars("##-"),
-    );
+    let resumed = offset > 0 && response.status() == StatusCode::PARTIAL_CONTENT;
+    let (mut file, start) = if resumed {
+        println!("Resuming download at {offset} bytes");
+        (OpenOptions::new().append(true).open(part)?, offset)
+    } else {
+        (File::create(part)?, 0)
+    };
+    let total_size = response.content_length().map(|length| length + start);
+
+    let pb = match total_size {
+        Some(total_size) => {
+            let pb = ProgressBar::new(total_size);
+            pb.set_style(
+                ProgressStyle::default_bar()
+                    .template("{msg} [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")?
+                    .progress_chars("##-"),
+            );
+            pb
+        }
+        None => {
+            let pb = ProgressBar::new_spinner();
+            pb.set_style(ProgressStyle::default_spinner().template("{msg} {spinner} {bytes}")?);
+            pb
+        }
+    };
     pb.set_message("Downloading");
+    pb.set_position(start);
 
-    let mut downloaded: u64 = 0;
-    let mut content = Vec::with_capacity(total_size as usize);
-    let mut reader = response.take(total_size);
-
-    let mut buffer = [0; 8192];
+    let mut downloaded = start;
+    let mut reader = response;
+    let mut buffer = vec![0; 64 * 1024];
     loop {
         let n = reader.read(&mut buffer)?;
         if n == 0 {
             break;
         }
-        content.extend_from_slice(&buffer[..n]);
+        file.write_all(&buffer[..n])?;
         downloaded += n as u64;
         pb.set_position(downloaded);
     }
+    file.flush()?;
+
+    if let Some(total_size) = total_size
+        && downloaded < total_size
+    {
+        pb.abandon();
+        return Err(anyhow!("Connection closed after {downloaded} of {total_size} bytes"));
+    }
+    pb.finish_with_message("Download complete");
+    Ok(())
+}
+
+/// Takes an exclusive lock for downloading to `part`, waiting while another process
+/// (possibly on another machine sharing the cache) holds it. Released when dropped.
+fn lock_download(part: &Path) -> Result<File> {
+    fs::create_dir_all(&*PARTIAL_DIR)?;
+    let mut path = part.as_os_str().to_owned();
+    path.push(".lock");
+    let lock = File::create(&path)?;
+    match lock.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            println!("Waiting for another download of the same file to finish...");
+            lock.lock()?;
+        }
+        // Some network filesystems cannot lock; downloading unlocked still works alone
+        Err(TryLockError::Error(e)) => eprintln!("WARNING: Cannot lock '{}': {e}", path.display()),
+    }
+    Ok(lock)
+}
+
+/// Fetches a `.tar.gz` through the download cache, checks it against `expected`
+/// (SHA-256) and unpacks it into `prefix`. Returns the archive's SHA-256.
+fn download_and_extract(url: &str, prefix: &Path, expected: Option<&str>) -> Result<String> {
+    let file_name = url.rsplit('/').next().unwrap_or("download");
+    // Without a known checksum, reuse whatever was downloaded from the same URL before
+    let lookup = || match expected {
+        Some(expected) => cache::get(expected).map(|path| (expected.to_string(), path)),
+        None => cache::find_url(url),
+    };
+    // Keyed by URL, so a resumed download never mixes two releases of the same file
+    let key = format!("{:x}", Sha256::digest(url.as_bytes()));
+    let part_path = PARTIAL_DIR.join(format!("{}-{file_name}", &key[..16]));
+
+    let mut lock = None;
+    let mut cached = lookup();
+    if cached.is_none() {
+        lock = Some(lock_download(&part_path)?);
+        // Another job sharing the cache may have finished it in the meantime
+        cached = lookup();
+    }
+    let (sha256, archive_path) = match cached {
+        Some(cached) => {
+            println!("Using cached download {}", cached.1.display());
+            cached
+        }
+        None => {
+            println!("Downloading from: {url}");
+            download(url, &part_path)?;
+            println!("Verifying...");
+            cache::insert(&part_path, url, file_name)?
+        }
+    };
+    drop(lock);
 
-    pb.finish_with_message("Download complete, extracting...");
+    if let Some(expected) = expected {
+        if sha256 != expected {
+            cache::evict(&sha256);
+            return Err(anyhow!("Checksum mismatch for {url}: expected {expected}, got {sha256}"));
+        }
+        println!("SHA-256 verified: {sha256}");
+    }
 
-    let tar_gz = GzDecoder::new(BufReader::new(Cursor::new(content)));
-    let mut archive = Archive::new(tar_gz);
-    archive.unpack(out_dir)?;
+    println!("Extracting...");
+    extract(&archive_path, prefix).with_context(|| format!("Failed to extract {file_name} into {}", prefix.display()))?;
 
     println!("Extraction finished.");
+    Ok(sha256)
+}
+
+/// Unpacks `archive_path` into a staging directory inside `prefix`, refusing entries that
+/// would land outside of it, then moves the result into place. Nothing is left behind on failure.
+fn extract(archive_path: &Path, prefix: &Path) -> Result<()> {
+    fs::create_dir_all(prefix)?;
+    // Inside the prefix, so the final renames stay on one filesystem
+    let staging = prefix.join(format!(".kff-extract-{}", process::id()));
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::create_dir(&staging)?;
+
+    let result = unpack_checked(archive_path, &staging).and_then(|()| move_into_place(&staging, prefix));
+    let _ = fs::remove_dir_all(&staging);
+    result
+}
+
+/// Only plain relative paths are allowed, without `..` or a root
+fn is_safe_path(path: &Path) -> bool {
+    path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+fn unpack_checked(archive_path: &Path, dest: &Path) -> Result<()> {
+    let mut archive = Archive::new(GzDecoder::new(BufReader::new(File::open(archive_path)?)));
+    let mut directories = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        if !is_safe_path(&path) {
+            return Err(anyhow!("Archive entry '{}' points outside the install directory", path.display()));
+        }
+        if entry.header().entry_type().is_hard_link()
+            && let Some(link) = entry.link_name()?
+            && !is_safe_path(&link)
+        {
+            return Err(anyhow!("Archive entry '{}' links outside the install directory", path.display()));
+        }
+        // Directory permissions are applied last, in case they are read-only
+        if entry.header().entry_type().is_dir() {
+            directories.push(entry);
+            continue;
+        }
+        entry.unpack_in(dest)?;
+    }
+    for mut directory in directories.into_iter().rev() {
+        directory.unpack_in(dest)?;
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+fn path_exists(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok()
+}
+
+fn remove_path(path: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// Renames the entries of `staging` into `prefix`. Directories that already exist there,
+/// like a shared `x-tools`, are entered one level deep so other toolchains in them are kept;
+/// what gets replaced is moved aside first and restored if a later rename fails.
+fn move_into_place(staging: &Path, prefix: &Path) -> Result<()> {
+    let mut moves = Vec::new();
+    for entry in fs::read_dir(staging)? {
+        let entry = entry?;
+        let dest = prefix.join(entry.file_name());
+        let shared = entry.file_type()?.is_dir() && fs::symlink_metadata(&dest).is_ok_and(|meta| meta.is_dir());
+        if shared {
+            for child in fs::read_dir(entry.path())? {
+                let child = child?;
+                moves.push((child.path(), dest.join(child.file_name())));
+            }
+        } else {
+            moves.push((entry.path(), dest));
+        }
+    }
+
+    rename_all(&moves, &prefix.join(format!(".kff-replaced-{}", process::id())))
+}
+
+/// Renames each `(src, dest)` pair, moving an existing `dest` into `replaced_dir` first.
+/// If one fails, the earlier ones are undone and the replaced paths restored.
+fn rename_all(moves: &[(PathBuf, PathBuf)], replaced_dir: &Path) -> Result<()> {
+    let mut done: Vec<(PathBuf, Option<PathBuf>)> = Vec::new();
+    let mut result = Ok(());
+    for (index, (src, dest)) in moves.iter().enumerate() {
+        let mut backup = None;
+        let step = (|| -> io::Result<()> {
+            if path_exists(dest) {
+                fs::create_dir_all(replaced_dir)?;
+                let aside = replaced_dir.join(index.to_string());
+                fs::rename(dest, &aside)?;
+                backup = Some(aside);
+            }
+            fs::rename(src, dest)
+        })();
+        if let Err(e) = step {
+            if let Some(aside) = &backup {
+                let _ = fs::rename(aside, dest);
+            }
+            result = Err(anyhow!(e).context(format!("Failed to move '{}' into place", dest.display())));
+            break;
+        }
+        done.push((dest.clone(), backup));
+    }
+
+    if result.is_err() {
+        for (dest, backup) in done.into_iter().rev() {
+            let _ = remove_path(&dest);
+            if let Some(aside) = backup {
+                let _ = fs::rename(aside, &dest);
+            }
+        }
+    }
+    let _ = fs::remove_dir_all(replaced_dir);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Mutex;
+    use std::thread;
+
+    const BODY: &[u8] = b"0123456789abcdefghij";
+
+    /// Local HTTP stand-in; `handler` gets the number of the request, its lowercased head
+    /// and the connection. Returns the URL to download and the heads of all requests.
+    fn stand_in<F>(handler: F) -> (String, Arc<Mutex<Vec<String>>>)
+    where
+        F: Fn(usize, &str, &mut TcpStream) + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/kindlehf.tar.gz", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        let handler = Arc::new(handler);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { break };
+                let (handler, seen) = (handler.clone(), seen.clone());
+                thread::spawn(move || {
+                    let mut head = Vec::new();
+                    let mut byte = [0; 1];
+                    while !head.ends_with(b"\r\n\r\n") && stream.read(&mut byte).is_ok_and(|n| n == 1) {
+                        head.push(byte[0]);
+                    }
+                    let head = String::from_utf8_lossy(&head).to_lowercase();
+                    let index = {
+                        let mut seen = seen.lock().unwrap();
+                        seen.push(head.clone());
+                        seen.len() - 1
+                    };
+                    handler(index, &head, &mut stream);
+                });
+            }
+        });
+        (url, requests)
+    }
+
+    /// Answers with a response announcing `length` bytes, of which only `body` is sent
+    fn reply(stream: &mut TcpStream, status: &str, length: usize, body: &[u8]) {
+        let head = format!("HTTP/1.1 {status}\r\nContent-Length: {length}\r\nConnection: close\r\n\r\n");
+        let _ = stream.write_all(head.as_bytes());
+        let _ = stream.write_all(body);
+        let _ = stream.flush();
+    }
+
+    fn fetch(url: &str, timeout: Duration, part: Option<&[u8]>) -> (Result<()>, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("kindlehf.tar.gz");
+        if let Some(part) = part {
+            fs::write(dir.path().join("kindlehf.tar.gz.part"), part).unwrap();
+        }
+        let client = Client::builder().timeout(timeout).build().unwrap();
+        let result = download_with(&client, url, &dest, Duration::from_millis(1));
+        (result, dir)
+    }
+
+    fn downloaded(dir: &tempfile::TempDir) -> Vec<u8> {
+        fs::read(dir.path().join("kindlehf.tar.gz")).unwrap()
+    }
+
+    #[test]
+    fn truncated_body_is_resumed() {
+        let (url, requests) = stand_in(|index, _, stream| match index {
+            0 => reply(stream, "200 OK", BODY.len(), &BODY[..8]),
+            _ => reply(stream, "206 Partial Content", BODY.len() - 8, &BODY[8..]),
+        });
+        let (result, dir) = fetch(&url, Duration::from_secs(10), None);
+        result.unwrap();
+        assert_eq!(downloaded(&dir), BODY);
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].contains("range: bytes=8-"));
+    }
+
+    #[test]
+    fn leftover_part_is_resumed() {
+        let (url, requests) = stand_in(|_, _, stream| reply(stream, "206 Partial Content", BODY.len() - 5, &BODY[5..]));
+        let (result, dir) = fetch(&url, Duration::from_secs(10), Some(&BODY[..5]));
+        result.unwrap();
+        assert_eq!(downloaded(&dir), BODY);
+        assert!(requests.lock().unwrap()[0].contains("range: bytes=5-"));
+    }
+
+    #[test]
+    fn range_not_satisfiable_starts_over() {
+        let (url, requests) = stand_in(|_, head, stream| {
+            if head.contains("range:") {
+                reply(stream, "416 Range Not Satisfiable", 0, b"");
+            } else {
+                reply(stream, "200 OK", BODY.len(), BODY);
+            }
+        });
+        let (result, dir) = fetch(&url, Duration::from_secs(10), Some(b"stale data from another release"));
+        result.unwrap();
+        assert_eq!(downloaded(&dir), BODY);
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(!requests[1].contains("range:"));
+    }
+
+    #[test]
+    fn ignored_range_replaces_the_part() {
+        let (url, _) = stand_in(|_, _, stream| reply(stream, "200 OK", BODY.len(), BODY));
+        let (result, dir) = fetch(&url, Duration::from_secs(10), Some(b"xxxxx"));
+        result.unwrap();
+        assert_eq!(downloaded(&dir), BODY);
+    }
+
+    #[test]
+    fn stalled_connection_is_retried() {
+        let (url, requests) = stand_in(|index, _, stream| match index {
+            0 => {
+                reply(stream, "200 OK", BODY.len(), &BODY[..4]);
+                thread::sleep(Duration::from_secs(5));
+            }
+            _ => reply(stream, "206 Partial Content", BODY.len() - 4, &BODY[4..]),
+        });
+        let started = Instant::now();
+        let (result, dir) = fetch(&url, Duration::from_millis(300), None);
+        result.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(4));
+        assert_eq!(downloaded(&dir), BODY);
+        assert!(requests.lock().unwrap()[1].contains("range: bytes=4-"));
+    }
+
+    #[test]
+    fn gives_up_after_the_retries() {
+        let (url, requests) = stand_in(|_, _, stream| reply(stream, "200 OK", BODY.len(), &BODY[..3]));
+        let (result, dir) = fetch(&url, Duration::from_secs(10), None);
+        assert!(result.is_err());
+        assert!(!dir.path().join("kindlehf.tar.gz").exists());
+        assert_eq!(requests.lock().unwrap().len(), DOWNLOAD_RETRIES as usize + 1);
+    }
+
+    #[test]
+    fn client_errors_are_not_retried() {
+        let (url, requests) = stand_in(|_, _, stream| reply(stream, "404 Not Found", 0, b""));
+        let (result, _dir) = fetch(&url, Duration::from_secs(10), None);
+        assert!(result.is_err());
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    /// Archive entry: a path written as is, bypassing the checks of `tar::Builder`,
+    /// and either file contents or the target of a hard link
+    enum Item<'a> {
+        File(&'a str, &'a [u8]),
+        HardLink(&'a str, &'a str),
+    }
+
+    fn archive(dir: &Path, items: &[Item]) -> PathBuf {
+        let path = dir.join("toolchain.tar.gz");
+        let mut builder = Builder::new(GzEncoder::new(File::create(&path).unwrap(), Compression::default()));
+        for item in items {
+            let mut header = tar::Header::new_gnu();
+            let (name, data) = match item {
+                Item::File(name, data) => {
+                    header.set_entry_type(tar::EntryType::Regular);
+                    header.set_size(data.len() as u64);
+                    (*name, *data)
+                }
+                Item::HardLink(name, target) => {
+                    header.set_entry_type(tar::EntryType::Link);
+                    header.set_size(0);
+                    header.as_gnu_mut().unwrap().linkname[..target.len()].copy_from_slice(target.as_bytes());
+                    (*name, &[][..])
+                }
+            };
+            header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_mode(0o755);
+            header.set_cksum();
+            builder.append(&header, data).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+        path
+    }
+
+    /// Every path below `dir`, relative to it
+    fn tree(dir: &Path) -> Vec<String> {
+        let mut paths: Vec<String> = walk(dir)
+            .into_iter()
+            .map(|path| path.strip_prefix(dir).unwrap().to_string_lossy().into_owned())
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    fn walk(dir: &Path) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                paths.extend(walk(&path));
+            }
+            paths.push(path);
+        }
+        paths
+    }
+
+    /// A prefix that already holds another target's toolchain
+    fn prefix() -> tempfile::TempDir {
+        let prefix = tempfile::tempdir().unwrap();
+        fs::create_dir_all(prefix.path().join("x-tools/arm-other/bin")).unwrap();
+        fs::write(prefix.path().join("x-tools/arm-other/bin/gcc"), "other").unwrap();
+        prefix
+    }
+
+    #[test]
+    fn safe_paths() {
+        assert!(is_safe_path(Path::new("x-tools/arm-kindlehf/bin/gcc")));
+        assert!(is_safe_path(Path::new("./x-tools")));
+        assert!(!is_safe_path(Path::new("/etc/passwd")));
+        assert!(!is_safe_path(Path::new("x-tools/../../escape")));
+        assert!(!is_safe_path(Path::new("..")));
+    }
+
+    #[test]
+    fn installs_next_to_other_toolchains() {
+        let work = tempfile::tempdir().unwrap();
+        let archive = archive(work.path(), &[Item::File("x-tools/arm-kindlehf/bin/gcc", b"gcc")]);
+        let prefix = prefix();
+        extract(&archive, prefix.path()).unwrap();
+        assert_eq!(
+            tree(prefix.path()),
+            [
+                "x-tools",
+                "x-tools/arm-kindlehf",
+                "x-tools/arm-kindlehf/bin",
+                "x-tools/arm-kindlehf/bin/gcc",
+                "x-tools/arm-other",
+                "x-tools/arm-other/bin",
+                "x-tools/arm-other/bin/gcc",
+            ]
+        );
+    }
+
+    #[test]
+    fn reinstall_replaces_the_toolchain() {
+        let work = tempfile::tempdir().unwrap();
+        let archive = archive(work.path(), &[Item::File("x-tools/arm-kindlehf/bin/gcc", b"new")]);
+        let prefix = prefix();
+        fs::create_dir_all(prefix.path().join("x-tools/arm-kindlehf/bin")).unwrap();
+        fs::write(prefix.path().join("x-tools/arm-kindlehf/bin/gcc"), "old").unwrap();
+        fs::write(prefix.path().join("x-tools/arm-kindlehf/stale"), "old").unwrap();
+
+        extract(&archive, prefix.path()).unwrap();
+        assert_eq!(fs::read_to_string(prefix.path().join("x-tools/arm-kindlehf/bin/gcc")).unwrap(), "new");
+        assert!(!prefix.path().join("x-tools/arm-kindlehf/stale").exists());
+        assert!(prefix.path().join("x-tools/arm-other/bin/gcc").exists());
+    }
+
+    fn assert_refused(items: &[Item]) {
+        let work = tempfile::tempdir().unwrap();
+        let archive = archive(work.path(), items);
+        let prefix = prefix();
+        let before = tree(prefix.path());
+        assert!(extract(&archive, prefix.path()).is_err());
+        assert_eq!(tree(prefix.path()), before);
+    }
+
+    #[test]
+    fn refuses_absolute_paths() {
+        assert_refused(&[Item::File("x-tools/arm-kindlehf/bin/gcc", b"gcc"), Item::File("/tmp/kff-escape", b"x")]);
+    }
+
+    #[test]
+    fn refuses_parent_components() {
+        assert_refused(&[Item::File("x-tools/arm-kindlehf/bin/gcc", b"gcc"), Item::File("x-tools/../../escape", b"x")]);
+    }
+
+    #[test]
+    fn refuses_hard_links_outside() {
+        assert_refused(&[
+            Item::File("x-tools/arm-kindlehf/bin/gcc", b"gcc"),
+            Item::HardLink("x-tools/arm-kindlehf/passwd", "../../../etc/passwd"),
+        ]);
+        assert_refused(&[Item::HardLink("x-tools/arm-kindlehf/passwd", "/etc/passwd")]);
+    }
+
+    #[test]
+    fn failed_rename_is_rolled_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let (new, installed) = (dir.path().join("new"), dir.path().join("installed"));
+        fs::write(&new, "new").unwrap();
+        fs::write(&installed, "old").unwrap();
+        let moves = [(new.clone(), installed.clone()), (dir.path().join("missing"), dir.path().join("other"))];
+
+        assert!(rename_all(&moves, &dir.path().join("replaced")).is_err());
+        assert_eq!(fs::read_to_string(&installed).unwrap(), "old");
+        assert!(!dir.path().join("other").exists());
+        assert!(!dir.path().join("replaced").exists());
+    }
+}
@@ -0,0 +1,322 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Result};
+use glob::Pattern;
+use regex::Regex;
+use serde_json::Value;
+use crate::config::STAGING_DIR;
+use crate::generator::{self, Bases};
+use crate::template::{environment, expand_partials, walk_files, Engine, Template};
+
+/// The published schema for `template.json`
+pub const SCHEMA: &str = include_str!("../template.schema.json");
+
+#[derive(Debug, PartialEq)]
+enum Severity {
+    Error,
+    Warning,
+}
+
+struct Diagnostic {
+    path: PathBuf,
+    line: Option<usize>,
+    severity: Severity,
+    message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path.display())?;
+        if let Some(line) = self.line {
+            write!(f, ":{line}")?;
+        }
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, ": {severity}: {}", self.message)
+    }
+}
+
+#[derive(Default)]
+struct Report {
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Report {
+    fn error(&mut self, path: &Path, line: Option<usize>, message: impl Into<String>) {
+        self.push(path, line, Severity::Error, message.into());
+    }
+
+    fn warning(&mut self, path: &Path, line: Option<usize>, message: impl Into<String>) {
+        self.push(path, line, Severity::Warning, message.into());
+    }
+
+    fn push(&mut self, path: &Path, line: Option<usize>, severity: Severity, message: String) {
+        self.diagnostics.push(Diagnostic { path: path.to_path_buf(), line, severity, message });
+    }
+
+    fn errors(&self) -> usize {
+        self.diagnostics.iter().filter(|d| d.severity == Severity::Error).count()
+    }
+}
+
+/// 1-based line of the first match of `re` in `text`
+fn line_of(text: &str, re: &str) -> Option<usize> {
+    let re = Regex::new(re).ok()?;
+    re.find(text).map(|m| text[..m.start()].lines().count().max(1))
+}
+
+/// 1-based line where the JSON string `value` first appears in `text`
+fn line_of_string(text: &str, value: &str) -> Option<usize> {
+    let quoted = serde_json::to_string(value).ok()?;
+    line_of(text, &regex::escape(&quoted))
+}
+
+/// Lints the template in `dir`, prints the diagnostics and returns the number of errors
+pub fn run(dir: &Path) -> Result<usize> {
+    if !dir.is_dir() {
+        return Err(anyhow!("'{}' is not a directory", dir.display()));
+    }
+
+    let extends = fs::read_to_string(dir.join("template.json"))
+        .ok()
+        .and_then(|text| serde_json::from_str::<Value>(&text).ok())
+        .is_some_and(|json| json.get("extends").is_some());
+
+    let report = if extends {
+        // Check what generate would see: the template merged with its bases
+        let staged = STAGING_DIR.join("lint");
+        println!("Linting '{}' merged with the templates it extends in {}", dir.display(), staged.display());
+        match generator::stage_files(dir, &staged, Bases::Installed) {
+            Ok(_) => lint(&staged)?,
+            Err(e) => {
+                let mut report = Report::default();
+                report.error(&dir.join("template.json"), None, format!("{e:#}"));
+                report
+            }
+        }
+    } else {
+        lint(dir)?
+    };
+    for diagnostic in &report.diagnostics {
+        println!("{diagnostic}");
+    }
+
+    let errors = report.errors();
+    let warnings = report.diagnostics.len() - errors;
+    println!("{errors} error(s), {warnings} warning(s)");
+    Ok(errors)
+}
+
+fn lint(dir: &Path) -> Result<Report> {
+    let mut report = Report::default();
+    let manifest = dir.join("template.json");
+
+    let text = match fs::read_to_string(&manifest) {
+        Ok(text) => text,
+        Err(e) => {
+            report.error(&manifest, None, format!("cannot read manifest: {e}"));
+            return Ok(report);
+        }
+    };
+
+    let json: Value = match serde_json::from_str(&text) {
+        Ok(json) => json,
+        Err(e) => {
+            report.error(&manifest, Some(e.line()), format!("invalid JSON: {e}"));
+            return Ok(report);
+        }
+    };
+
+    let schema: Value = serde_json::from_str(SCHEMA)?;
+    let validator = jsonschema::validator_for(&schema)
+        .map_err(|e| anyhow!("invalid bundled schema: {e}"))?;
+    for error in validator.iter_errors(&json) {
+        let pointer = error.instance_path.to_string();
+        let pointer = if pointer.is_empty() { "/".to_string() } else { pointer };
+        report.error(&manifest, None, format!("{pointer}: {error}"));
+    }
+    if report.errors() > 0 {
+        return Ok(report);
+    }
+
+    let template = match Template::parse_from_file(&manifest) {
+        Ok(template) => template,
+        Err(e) => {
+            report.error(&manifest, None, e.to_string());
+            return Ok(report);
+        }
+    };
+
+    let env = environment();
+    // Everything that can refer to a question, for the unused-question check
+    let mut usages: Vec<String> = Vec::new();
+
+    let partials_dir = template.partials.as_ref().map(|partials| dir.join(partials));
+    if let Some(partials_dir) = &partials_dir
+        && !partials_dir.is_dir()
+    {
+        report.error(
+            &manifest,
+            line_of(&text, r#""partials""#),
+            format!("partials directory '{}' does not exist", template.partials.as_deref().unwrap_or_default()),
+        );
+    }
+    // File contents as they are rendered, with partials included
+    let expand = |contents: String| match &partials_dir {
+        Some(partials_dir) if contents.contains("{{>") => expand_partials(&contents, partials_dir),
+        _ => Ok(contents),
+    };
+
+    for file_entry in &template.files {
+        let line = line_of_string(&text, &file_entry.file);
+        let path = dir.join(&file_entry.file);
+        let contents = match fs::read_to_string(&path).map_err(anyhow::Error::from).and_then(expand) {
+            Ok(contents) => Some(contents),
+            Err(e) => {
+                report.error(&manifest, line, format!("file '{}' cannot be read: {e}", file_entry.file));
+                None
+            }
+        };
+
+        for placeholder in &file_entry.placeholders {
+            let line = line_of_string(&text, &placeholder.placeholder);
+            if let Some(contents) = &contents
+                && !contents.contains(&placeholder.placeholder)
+            {
+                report.error(
+                    &manifest,
+                    line,
+                    format!("placeholder '{}' does not appear in '{}'", placeholder.placeholder, file_entry.file),
+                );
+            }
+            if !template.questions.iter().any(|q| q.name == placeholder.question()) {
+                report.error(
+                    &manifest,
+                    line,
+                    format!("replacement '{}' does not refer to a declared question", placeholder.replacement),
+                );
+            }
+            usages.push(placeholder.question().to_string());
+        }
+    }
+
+    if let Some(key) = json.get("output_key").and_then(Value::as_str)
+        && !template.questions.iter().any(|q| q.name == key)
+    {
+        report.error(&manifest, line_of_string(&text, key), format!("output_key '{key}' does not refer to a declared question"));
+    }
+
+    for question in &template.questions {
+        if let Some(when) = &question.when {
+            if let Err(e) = env.compile_expression(when) {
+                report.error(&manifest, line_of_string(&text, when), format!("invalid condition '{when}': {e}"));
+            }
+            usages.push(when.clone());
+        }
+        if let Value::String(default) = &question.default {
+            usages.push(default.clone());
+        }
+    }
+
+    let conditions = template.include_if.iter().chain(template.exclude_if.iter());
+    let patterns = template.ignore.iter().chain(template.include_if.keys()).chain(template.exclude_if.keys());
+    for (_, expr) in conditions {
+        if let Err(e) = env.compile_expression(expr) {
+            report.error(&manifest, line_of_string(&text, expr), format!("invalid condition '{expr}': {e}"));
+        }
+        usages.push(expr.clone());
+    }
+
+    let files = walk_files(dir)?;
+    let relative: Vec<String> = files
+        .iter()
+        .filter_map(|path| path.strip_prefix(dir).ok())
+        .map(|path| path.to_string_lossy().replace('\\', "/"))
+        .collect();
+    // Patterns apply to the generated files, where `.tmpl` has been stripped
+    let rendered: Vec<&str> = relative
+        .iter()
+        .map(|name| match template.engine {
+            Engine::Jinja => name.strip_suffix(".tmpl").unwrap_or(name),
+            Engine::Placeholder => name,
+        })
+        .collect();
+
+    for pattern in patterns {
+        let line = line_of_string(&text, pattern);
+        match Pattern::new(pattern.trim_end_matches('/')) {
+            Ok(glob) => {
+                let matches_any = rendered.iter().any(|path| {
+                    glob.matches(path)
+                        || Path::new(path).ancestors().any(|parent| glob.matches(&parent.to_string_lossy()))
+                });
+                if !matches_any {
+                    report.warning(&manifest, line, format!("pattern '{pattern}' matches nothing"));
+                }
+            }
+            Err(e) => report.error(&manifest, line, format!("invalid pattern '{pattern}': {e}")),
+        }
+    }
+
+    for hook in &template.post_generate {
+        usages.push(hook.command.clone());
+        usages.extend(hook.args.iter().cloned());
+        usages.extend(hook.cwd.iter().cloned());
+    }
+
+    for (path, name) in files.iter().zip(&relative) {
+        if let Some(partials_dir) = &partials_dir
+            && path.starts_with(partials_dir)
+        {
+            if let Ok(source) = fs::read_to_string(path) {
+                usages.push(source);
+            }
+            continue;
+        }
+
+        if name.contains("{{") || name.contains("{%") {
+            if let Err(e) = env.template_from_str(name) {
+                report.error(path, None, format!("invalid expression in path: {e}"));
+            }
+            usages.push(name.clone());
+        }
+
+        if template.engine == Engine::Jinja && name.ends_with(".tmpl") {
+            let Ok(source) = fs::read_to_string(path) else {
+                report.error(path, None, "template file is not valid UTF-8");
+                continue;
+            };
+            let source = match expand(source.clone()) {
+                Ok(source) => source,
+                Err(e) => {
+                    report.error(path, None, format!("{e:#}"));
+                    usages.push(source);
+                    continue;
+                }
+            };
+            if let Err(e) = env.template_from_named_str(name, &source) {
+                report.error(path, e.line(), e.to_string());
+            }
+            usages.push(source);
+        }
+    }
+
+    for question in &template.questions {
+        if question.name == template.output_key() {
+            continue;
+        }
+        let word = Regex::new(&format!(r"\b{}\b", regex::escape(&question.name)))?;
+        if !usages.iter().any(|usage| word.is_match(usage)) {
+            report.warning(
+                &manifest,
+                line_of(&text, &format!(r#""name"\s*:\s*{}"#, regex::escape(&serde_json::to_string(&question.name)?))),
+                format!("question '{}' is never used", question.name),
+            );
+        }
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,63 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Where the lock file lives inside a generated project
+pub const LOCK_FILE: &str = ".kff/template.lock";
+
+/// Records which template, at which commit and with which answers a project was generated
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TemplateLock {
+    pub(crate) template: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) registry: Option<String>,
+    pub(crate) url: Option<String>,
+    #[serde(rename = "ref", default, skip_serializing_if = "Option::is_none")]
+    pub(crate) git_ref: Option<String>,
+    pub(crate) commit: Option<String>,
+    /// Directory of the template inside its repository
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) subdir: Option<String>,
+    /// Templates it `extends`, nearest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) bases: Vec<BaseLock>,
+    pub(crate) answers: BTreeMap<String, Value>,
+}
+
+/// A base template as it was used, so the same rendering can be rebuilt later.
+/// Bases given by a relative path are part of the extending template and only keep the name.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BaseLock {
+    pub(crate) template: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) commit: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) subdir: Option<String>,
+}
+
+impl TemplateLock {
+    pub fn path(project: &Path) -> PathBuf {
+        project.join(LOCK_FILE)
+    }
+
+    pub fn read(project: &Path) -> Result<Self> {
+        let path = Self::path(project);
+        let data = fs::read_to_string(&path)
+            .with_context(|| format!("'{}' was not generated by kff (no {LOCK_FILE})", project.display()))?;
+        serde_json::from_str(&data).with_context(|| format!("Invalid lock file '{}'", path.display()))
+    }
+
+    pub fn write(&self, project: &Path) -> Result<()> {
+        let path = Self::path(project);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, serde_json::to_string_pretty(self)? + "\n")?;
+        Ok(())
+    }
+}
\ No newline at end of file
@@ -1,12 +1,6 @@
-use std::collections::HashMap;
-use std::env::{temp_dir};
-use std::path::{PathBuf};
-use std::{process};
+use std::process;
+use std::sync::atomic::Ordering;
 use clap::Parser;
-use fs_extra::dir::{copy, CopyOptions};
-use fs_extra::{dir, file};
-use crate::config::TEMPLATES_DIR;
-use crate::repository::{Repo, RepoSource};
 
 mod cli;
 mod template;
@@ -14,99 +8,35 @@ mod repository;
 mod config;
 mod doctor;
 mod installer;
+mod hooks;
+mod lint;
+mod generator;
+mod catalog;
+mod lock;
+mod update;
+mod templates;
+mod cache;
 
 fn main() -> anyhow::Result<()> {
     let args = cli::CliArgs::parse();
+    config::OFFLINE.store(args.offline, Ordering::Relaxed);
+    config::INSECURE.store(args.insecure, Ordering::Relaxed);
 
     match args.command {
         cli::Commands::Generate(generate_args) => {
-            println!("Kindle Fucking Forge generate started...");
-            println!("{}", config::ASCII_ART);
-            println!("Searching for the {} template in the kff global repository...", generate_args.name);
-            let repo = match repository::search(&generate_args.name) {
-                Ok(repo) => repo,
-                Err(e) => {
-                    // Couldn't get the remote repo -> try the local
-                    let local_path = TEMPLATES_DIR.join(&generate_args.name);
-                    if local_path.exists() && local_path.is_dir() {
-                        Repo::new(&generate_args.name, RepoSource::Local)
-                    } else {
-                        eprintln!("ERROR: Template '{}' not found remotely and no local copy available", generate_args.name);
-                        process::exit(1);
-                    }
-                }
-            };
-
-            let use_local = match repo.url {
-                RepoSource::Local => true,
-                RepoSource::Remote(_) => {
-                    let local_path = TEMPLATES_DIR.join(&repo.name);
-                    if local_path.exists() && local_path.is_dir() {
-                        repository::choose_local_or_download(&Repo::new(&generate_args.name, RepoSource::Local))?
-                    } else {
-                        false
-                    }
-                }
-            };
-
-            if use_local {
-                println!("Using local template '{}'", repo.name);
-            } else {
-                println!("Downloading template '{}'", repo.name);
-                if let Err(e) = repository::download(&repo) {
-                    eprintln!("ERROR: {e}");
-                    process::exit(1);
-                }
-            }
-
-            println!("Parsing the template.json file...");
-            let tdir: PathBuf = TEMPLATES_DIR.join(&generate_args.name);
-
-            // tmp dir
-            let tmp_template_path = temp_dir().join("kff").join(&generate_args.name);
-            if tmp_template_path.exists() {
-                std::fs::remove_dir_all(&tmp_template_path)?;
-            }
-
-            // Copying a template to a tmp dir
-            let mut options = CopyOptions::new();
-            options.overwrite = true;
-            options.copy_inside = true;
-            copy(&tdir, &tmp_template_path, &options)?;
-
-            match template::Template::parse_from_file(&tmp_template_path.join("template.json")) {
-                Ok(repo) => {
-                    println!("Starting the '{}' template generator", generate_args.name);
-                    let answers: HashMap<String, String> = repo.ask_questions();
-                    repo.apply_replacements(&answers, &tmp_template_path)?;
-
-                    // Unnecessary files/dirs need to be deleted
-                    file::remove(&tmp_template_path.join("template.json"))?;
-                    dir::remove(&tmp_template_path.join(".git"))?;
-
-                    let out_path = std::env::current_dir()?.join(&answers.get("app_name").unwrap_or(&generate_args.name));
-                    if out_path.exists() {
-                        std::fs::remove_dir_all(&out_path)?;
-                    }
-
-                    copy(&tmp_template_path, &out_path, &options)?;
-
-                    println!("Project generated at: {}", out_path.display());
-                }
-                Err(e) => {
-                    eprintln!("ERROR: {e}");
-                    process::exit(1);
-                }
-            }
+            generator::run(&generate_args)?;
+        }
+        cli::Commands::Update(update_args) => {
+            update::run(&update_args)?;
         }
         cli::Commands::Doctor => {
             doctor::run()?;
         }
         cli::Commands::Install(installer_args) => {
-            match installer_args.names.get(0).map(String::as_str) {
+            match installer_args.names.first().map(String::as_str) {
                 Some("toolchain") => {
                     if let Some(target) = installer_args.names.get(1) {
-                        installer::toolchain(target)?;
+                        installer::toolchain(target, installer_args.version.as_deref(), installer_args.prefix.as_deref())?;
                     } else {
                         eprintln!("[ERROR] Missing toolchain target. Example: `kff install toolchain kindlehf`");
                     }
@@ -126,6 +56,33 @@ fn main() -> anyhow::Result<()> {
                 }
             }
         }
+        cli::Commands::Toolchain(command) => match command {
+            cli::ToolchainCommands::Versions(args) => installer::toolchain_versions(&args.target)?,
+        },
+        cli::Commands::Cache(command) => match command {
+            cli::CacheCommands::List => cache::list()?,
+            cli::CacheCommands::Size => cache::size()?,
+            cli::CacheCommands::Clean => cache::clean()?,
+        },
+        cli::Commands::Registry(command) => match command {
+            cli::RegistryCommands::Add(args) => repository::add_registry(&args.name, &args.url, args.priority, &args.keys)?,
+            cli::RegistryCommands::Remove(args) => repository::remove_registry(&args.name)?,
+            cli::RegistryCommands::List => repository::list_registries()?,
+            cli::RegistryCommands::Update(args) => repository::update_registries(args.name.as_deref())?,
+        },
+        cli::Commands::Template(command) => match command {
+            cli::TemplateCommands::Lint(lint_args) => {
+                if lint::run(&lint_args.dir)? > 0 {
+                    process::exit(1);
+                }
+            }
+            cli::TemplateCommands::List => catalog::list()?,
+            cli::TemplateCommands::Search(args) => catalog::search(&args.query)?,
+            cli::TemplateCommands::Install(args) => templates::install(&args.source, args.git_ref.as_deref(), args.force)?,
+            cli::TemplateCommands::Update(args) => templates::update(args.name.as_deref())?,
+            cli::TemplateCommands::Remove(args) => templates::remove(&args.name)?,
+            cli::TemplateCommands::Prune => templates::prune()?,
+        },
     }
 
     Ok(())
@@ -1,41 +1,744 @@
-use std::io;
+use std::{fs, io};
 use std::io::Write;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, Context};
+use fs_extra::dir::{copy, CopyOptions};
 use git2::Repository;
-use serde::Deserialize;
+use reqwest::blocking::Client;
+use reqwest::header::{HeaderName, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
 
-use crate::config::{REPOSITORY, TEMPLATES_DIR};
+use minisign_verify::{PublicKey, Signature};
+use crate::config::{
+    ensure_online, ensure_trusted, is_offline, Config, Registry, DEFAULT_INDEX_TTL, DEFAULT_REGISTRY, HOME, REGISTRY_CACHE_DIR,
+    STAGING_DIR, TEMPLATES_DIR,
+};
+use crate::lock::BaseLock;
 
 #[derive(Deserialize, Debug)]
 #[serde(untagged)]
 pub enum RepoSource {
     Local,
     Remote(String), // git url
+    /// Template directory used in place, from a local registry
+    #[serde(skip)]
+    Path(PathBuf),
 }
 
 #[derive(Deserialize, Debug)]
 pub struct Repo {
     pub(crate) name: String,
     pub(crate) url: RepoSource,
+    #[serde(default)]
+    pub(crate) description: Option<String>,
+    #[serde(default)]
+    pub(crate) tags: Vec<String>,
+    #[serde(default)]
+    pub(crate) maintainer: Option<String>,
+    /// Kindle targets the template builds for (e.g. `kindlehf`)
+    #[serde(default)]
+    pub(crate) targets: Vec<String>,
+    /// Branch, tag or commit to use instead of the default branch
+    #[serde(default, rename = "ref")]
+    pub(crate) git_ref: Option<String>,
+    /// Commit the registry vouches for; any other commit is refused without `--insecure`
+    #[serde(default)]
+    pub(crate) commit: Option<String>,
+    /// Directory of the template inside the repository, for repositories holding several templates
+    #[serde(default)]
+    pub(crate) subdir: Option<String>,
+    /// Registry the template was found in
+    #[serde(skip)]
+    pub(crate) registry: Option<String>,
 }
 impl Repo {
     pub fn new(name: &str, url: RepoSource) -> Self {
         Self {
             name: name.to_string(),
             url,
+            description: None,
+            tags: Vec::new(),
+            maintainer: None,
+            targets: Vec::new(),
+            git_ref: None,
+            commit: None,
+            subdir: None,
+            registry: None,
         }
     }
+
+    /// Ref to check out when none was asked for: the pinned commit, else the entry's `ref`
+    pub fn default_ref(&self) -> Option<&str> {
+        self.commit.as_deref().or(self.git_ref.as_deref())
+    }
+}
+
+/// Directory holding template.json inside a checkout, `subdir` being relative to its root
+pub fn template_dir(checkout: &Path, subdir: Option<&str>) -> anyhow::Result<PathBuf> {
+    let Some(subdir) = subdir else {
+        return Ok(checkout.to_path_buf());
+    };
+    if !Path::new(subdir).components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+        return Err(anyhow!("Invalid template subdirectory '{subdir}': must be a relative path inside the repository"));
+    }
+    let dir = checkout.join(subdir);
+    if !dir.join("template.json").is_file() {
+        return Err(anyhow!("No template.json in '{subdir}' of '{}'", checkout.display()));
+    }
+    Ok(dir)
+}
+
+/// Whether an `extends` value is a path relative to the extending template
+pub fn is_relative_base(name: &str) -> bool {
+    name.starts_with("./") || name.starts_with("../")
+}
+
+/// Finds the directory of the template named in `extends`, downloading it if needed, and
+/// describes what was used for the lock file. Paths starting with `./` or `../` are relative
+/// to the extending template. With `latest`, a downloaded template is not used as it is but
+/// at the newest commit of its upstream branch.
+pub fn resolve_base(name: &str, from: &Path, latest: bool) -> anyhow::Result<(PathBuf, BaseLock)> {
+    let (name, git_ref) = split_ref(name);
+    let relative = is_relative_base(name);
+    let source = if relative {
+        from_source(&std::path::absolute(from.join(name))?.to_string_lossy())?
+    } else {
+        from_source(name)?
+    };
+
+    let repo = match source {
+        Some(repo) => repo,
+        None => match search(name) {
+            Ok(repo) => repo,
+            Err(e) => {
+                let (_, template_name) = split_name(name);
+                if !TEMPLATES_DIR.join(template_name).is_dir() {
+                    return Err(e.context(format!("Cannot resolve base template '{name}'")));
+                }
+                eprintln!("WARNING: {e:#}; falling back to the local copy");
+                Repo::new(template_name, RepoSource::Local)
+            }
+        },
+    };
+    // A ref given in `extends` is a version picked by the template author, not the registry's
+    let chosen_ref = git_ref;
+    let git_ref = match &repo.url {
+        RepoSource::Path(_) => git_ref.or(repo.git_ref.as_deref()),
+        _ => git_ref.or(repo.default_ref()),
+    };
+
+    let (checkout, url) = match &repo.url {
+        RepoSource::Path(path) => {
+            if let Some(git_ref) = git_ref {
+                return Err(anyhow!("Cannot check out '{git_ref}': base template '{}' is used in place", repo.name));
+            }
+            (path.clone(), Some(path.to_string_lossy().into_owned()))
+        }
+        RepoSource::Remote(url) => {
+            let local = TEMPLATES_DIR.join(&repo.name);
+            if !local.is_dir() || origin_url(&local).is_none_or(|origin| &origin != url) {
+                download(&repo, chosen_ref)?;
+            }
+            (local, Some(url.clone()))
+        }
+        RepoSource::Local => {
+            let local = TEMPLATES_DIR.join(&repo.name);
+            let url = origin_url(&local);
+            (local, url)
+        }
+    };
+    let upstream = latest && !is_offline() && !matches!(repo.url, RepoSource::Path(_)) && origin_url(&checkout).is_some();
+    let git_ref = git_ref.or(upstream.then_some("HEAD"));
+    // The downloaded template stays on its branch, like in `generate`
+    let (checkout, commit) = match git_ref {
+        Some(git_ref) => {
+            let copy = STAGING_DIR.join("bases").join(&repo.name);
+            let commit = checkout_copy(&checkout, git_ref, &copy)?;
+            (copy, Some(commit))
+        }
+        None => {
+            let commit = head_commit(&checkout);
+            (checkout, commit)
+        }
+    };
+
+    if chosen_ref.is_none() {
+        verify_pin(&repo, commit.as_deref())?;
+    }
+
+    let lock = if relative {
+        BaseLock { template: name.to_string(), url: None, commit: None, subdir: None }
+    } else {
+        BaseLock { template: repo.name.clone(), url, commit, subdir: repo.subdir.clone() }
+    };
+    Ok((template_dir(&checkout, repo.subdir.as_deref())?, lock))
 }
 
-pub fn choose_local_or_download(repo: &Repo) -> anyhow::Result<bool> {
+/// Rebuilds a base template at the commit recorded in a lock file
+pub fn checkout_base(base: &BaseLock) -> anyhow::Result<PathBuf> {
+    let local = TEMPLATES_DIR.join(&base.template);
+    let Some(commit) = &base.commit else {
+        // Not a git checkout, so only its current state is available
+        let dir = match &base.url {
+            Some(url) if !is_url(url) => PathBuf::from(url),
+            _ => local,
+        };
+        return template_dir(&dir, base.subdir.as_deref());
+    };
+
+    let dest = STAGING_DIR.join("bases").join(&base.template);
+    let same_source = base.url.is_none() || origin_url(&local) == base.url;
+    if Repository::open(&local).is_ok() && same_source {
+        checkout_copy(&local, commit, &dest)?;
+    } else {
+        match &base.url {
+            Some(url) if is_url(url) => {
+                clone_into(url, &dest)?;
+                checkout_ref(&dest, commit)?;
+            }
+            Some(path) => {
+                checkout_copy(Path::new(path), commit, &dest)?;
+            }
+            None => return Err(anyhow!("Base template '{}' is not available", base.template)),
+        }
+    }
+    template_dir(&dest, base.subdir.as_deref())
+        .with_context(|| format!("Base template '{}' at {commit}", base.template))
+}
+
+/// Where a registry's index lives
+enum Location {
+    Remote(String),
+    File(PathBuf),
+    Dir(PathBuf),
+}
+
+fn is_url(s: &str) -> bool {
+    s.contains("://") || s.starts_with("git@")
+}
+
+/// Builds an entry for a git URL, `gh:user/repo` or a local template directory given instead of a template name
+pub fn from_source(source: &str) -> anyhow::Result<Option<Repo>> {
+    if let Some(repo) = source.strip_prefix("gh:") {
+        return from_source(&format!("https://github.com/{}.git", repo.trim_end_matches(".git")));
+    }
+    if is_url(source) {
+        let name = source
+            .trim_end_matches('/')
+            .trim_end_matches(".git")
+            .rsplit(['/', ':'])
+            .next()
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| anyhow!("Cannot derive a template name from '{source}'"))?;
+        return Ok(Some(Repo::new(name, RepoSource::Remote(source.to_string()))));
+    }
+
+    if source.starts_with('.') || source.starts_with('/') || source.starts_with('~') {
+        let path = absolute_path(source)?;
+        if !path.join("template.json").is_file() {
+            return Err(anyhow!("'{}' is not a template directory (no template.json)", path.display()));
+        }
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or_else(|| anyhow!("Cannot derive a template name from '{source}'"))?;
+        return Ok(Some(Repo::new(&name, RepoSource::Path(path))));
+    }
+
+    Ok(None)
+}
+
+/// Turns a local registry path into an absolute one, expanding `~/`
+pub fn absolute_path(path: &str) -> anyhow::Result<PathBuf> {
+    let path = match path.strip_prefix("~/") {
+        Some(rest) => HOME.join(rest),
+        None => PathBuf::from(path),
+    };
+    Ok(std::path::absolute(path)?)
+}
+
+fn location(registry: &Registry) -> anyhow::Result<Location> {
+    let url = registry.url.as_str();
+    if url.starts_with("http://") || url.starts_with("https://") {
+        return Ok(Location::Remote(url.to_string()));
+    }
+
+    let path = absolute_path(url.strip_prefix("file://").unwrap_or(url))?;
+    if path.is_dir() {
+        Ok(Location::Dir(path))
+    } else if path.is_file() {
+        Ok(Location::File(path))
+    } else {
+        Err(anyhow!("Registry path '{}' does not exist", path.display()))
+    }
+}
+
+fn parse_index(data: &str, base: Option<&Path>) -> anyhow::Result<Vec<Repo>> {
+    let mut repos: Vec<Repo> = serde_json::from_str(data).context("Invalid registry index")?;
+
+    // Entries of a local index may point to template directories relative to it
+    if let Some(base) = base {
+        for repo in &mut repos {
+            if let RepoSource::Remote(url) = &repo.url
+                && !is_url(url)
+            {
+                repo.url = RepoSource::Path(base.join(url.trim_start_matches("./")));
+            }
+        }
+    }
+    Ok(repos)
+}
+
+/// Validators and age of a cached registry index
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+struct CacheMeta {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    /// Unix time of the last successful check against the remote
+    checked_at: u64,
+    /// Detached minisign signature of the index, for registries with trusted keys
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+fn cache_paths(registry: &Registry) -> (PathBuf, PathBuf) {
+    (
+        REGISTRY_CACHE_DIR.join(format!("{}.json", registry.name)),
+        REGISTRY_CACHE_DIR.join(format!("{}.meta.json", registry.name)),
+    )
+}
+
+/// Returns the cached index and its metadata, if it was fetched from `url`
+fn read_cache(registry: &Registry, url: &str) -> Option<(String, CacheMeta)> {
+    let (index_path, meta_path) = cache_paths(registry);
+    let meta: CacheMeta = serde_json::from_str(&fs::read_to_string(meta_path).ok()?).ok()?;
+    if meta.url != url {
+        return None;
+    }
+    Some((fs::read_to_string(index_path).ok()?, meta))
+}
+
+fn write_cache(registry: &Registry, data: &str, meta: &CacheMeta) -> anyhow::Result<()> {
+    let (index_path, meta_path) = cache_paths(registry);
+    fs::create_dir_all(&*REGISTRY_CACHE_DIR)?;
+    fs::write(index_path, data)?;
+    fs::write(meta_path, serde_json::to_string_pretty(meta)?)?;
+    Ok(())
+}
+
+fn parse_key(key: &str) -> anyhow::Result<PublicKey> {
+    // Either the base64 key alone or the whole contents of a minisign .pub file
+    let key = key.trim();
+    let parsed = if key.contains('\n') { PublicKey::decode(key) } else { PublicKey::from_base64(key) };
+    parsed.map_err(|e| anyhow!("Invalid minisign public key '{key}': {e}"))
+}
+
+/// Checks the detached signature of an index against the registry's trusted keys
+fn verify_index(registry: &Registry, data: &str, signature: Option<&str>) -> anyhow::Result<()> {
+    if registry.keys.is_empty() {
+        return Ok(());
+    }
+    let Some(signature) = signature else {
+        return ensure_trusted(format!("The index of registry '{}' is not signed", registry.name));
+    };
+
+    let verified = Signature::decode(signature).is_ok_and(|signature| {
+        registry
+            .keys
+            .iter()
+            .filter_map(|key| parse_key(key).ok())
+            .any(|key| key.verify(data.as_bytes(), &signature, false).is_ok())
+    });
+    if !verified {
+        return ensure_trusted(format!(
+            "The index of registry '{}' is not signed by any of its trusted keys",
+            registry.name
+        ));
+    }
+    Ok(())
+}
+
+/// Reads the `.minisig` file next to a local index, if there is one
+fn local_signature(index: &Path) -> Option<String> {
+    let mut path = index.as_os_str().to_owned();
+    path.push(".minisig");
+    fs::read_to_string(path).ok()
+}
+
+fn download_signature(url: &str) -> anyhow::Result<String> {
+    let url = format!("{url}.minisig");
+    let client = Client::builder().user_agent("kff").build()?;
+    let response = client.get(&url).send().with_context(|| format!("Failed to fetch {url}"))?;
+    Ok(response.error_for_status()?.text()?)
+}
+
+/// Loads a remote index, using the cache while it is fresh (or always when offline).
+/// `force` ignores the TTL and fails if the remote does; otherwise a cached copy is
+/// used with a warning when the remote is unreachable.
+fn fetch_index(registry: &Registry, url: &str, force: bool) -> anyhow::Result<Vec<Repo>> {
+    let cached = read_cache(registry, url);
+
+    if is_offline() {
+        let (data, meta) = cached.ok_or_else(|| anyhow!("No cached index for registry '{}' (offline)", registry.name))?;
+        verify_index(registry, &data, meta.signature.as_deref())?;
+        return parse_index(&data, None);
+    }
+
+    let ttl = Config::load()?.index_ttl.unwrap_or(DEFAULT_INDEX_TTL);
+    if let Some((data, meta)) = &cached
+        && !force
+        && now().saturating_sub(meta.checked_at) < ttl
+        && (registry.keys.is_empty() || meta.signature.is_some())
+    {
+        verify_index(registry, data, meta.signature.as_deref())?;
+        return parse_index(data, None);
+    }
+
+    let fetched = download_index(url, cached.as_ref().map(|(_, meta)| meta)).and_then(|fetched| {
+        let (data, mut meta) = match fetched {
+            Some(fetched) => fetched,
+            None => {
+                // 304 Not Modified
+                let (data, mut meta) = cached
+                    .clone()
+                    .ok_or_else(|| anyhow!("Registry '{}' answered 304 without a cached index", registry.name))?;
+                meta.checked_at = now();
+                (data, meta)
+            }
+        };
+        if !registry.keys.is_empty() && meta.signature.is_none() {
+            meta.signature = Some(download_signature(url)?);
+        }
+        Ok((data, meta))
+    });
+
+    match fetched {
+        Ok((data, meta)) => {
+            verify_index(registry, &data, meta.signature.as_deref())?;
+            let repos = parse_index(&data, None)?;
+            write_cache(registry, &data, &meta)?;
+            Ok(repos)
+        }
+        Err(e) => match cached {
+            Some((data, meta)) if !force => {
+                eprintln!("WARNING: registry '{}' is unreachable, using the cached index: {e:#}", registry.name);
+                verify_index(registry, &data, meta.signature.as_deref())?;
+                parse_index(&data, None)
+            }
+            _ => Err(e),
+        },
+    }
+}
+
+/// Conditional GET of an index; `None` means the cached copy is still current
+fn download_index(url: &str, cached: Option<&CacheMeta>) -> anyhow::Result<Option<(String, CacheMeta)>> {
+    let client = Client::builder().user_agent("kff").build()?;
+    let mut request = client.get(url);
+    if let Some(meta) = cached {
+        if let Some(etag) = &meta.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &meta.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+
+    let response = request.send().with_context(|| format!("Failed to fetch {url}"))?;
+    if response.status() == StatusCode::NOT_MODIFIED && cached.is_some() {
+        return Ok(None);
+    }
+    let response = response.error_for_status()?;
+
+    let header = |name: HeaderName| {
+        response.headers().get(name).and_then(|value| value.to_str().ok()).map(str::to_string)
+    };
+    let meta = CacheMeta {
+        url: url.to_string(),
+        etag: header(ETAG),
+        last_modified: header(LAST_MODIFIED),
+        checked_at: now(),
+        signature: None,
+    };
+    Ok(Some((response.text()?, meta)))
+}
+
+/// Refreshes the cached index of the registry `name`, or of every remote registry
+pub fn update_registries(name: Option<&str>) -> anyhow::Result<()> {
+    ensure_online("update registries")?;
+
+    let registries: Vec<Registry> = Config::load()?
+        .registries()
+        .into_iter()
+        .filter(|r| name.is_none_or(|name| r.name == name))
+        .collect();
+    if let Some(name) = name
+        && registries.is_empty()
+    {
+        return Err(anyhow!("Unknown registry '{name}'"));
+    }
+
+    for registry in &registries {
+        match location(registry) {
+            Ok(Location::Remote(url)) => match fetch_index(registry, &url, true) {
+                Ok(repos) => println!("Updated '{}' ({} templates)", registry.name, repos.len()),
+                Err(e) => eprintln!("WARNING: failed to update '{}': {e:#}", registry.name),
+            },
+            Ok(_) => println!("'{}' is a local registry, nothing to update", registry.name),
+            Err(e) => eprintln!("WARNING: '{}': {e:#}", registry.name),
+        }
+    }
+    Ok(())
+}
+
+/// Loads the list of templates offered by a registry
+pub fn index(registry: &Registry) -> anyhow::Result<Vec<Repo>> {
+    match location(registry)? {
+        Location::Remote(url) => fetch_index(registry, &url, false),
+        Location::File(path) => {
+            let data = fs::read_to_string(&path)?;
+            verify_index(registry, &data, local_signature(&path).as_deref())?;
+            parse_index(&data, path.parent())
+        }
+        Location::Dir(dir) => {
+            let index = dir.join("templates.json");
+            if index.is_file() {
+                let data = fs::read_to_string(&index)?;
+                verify_index(registry, &data, local_signature(&index).as_deref())?;
+                return parse_index(&data, Some(&dir));
+            }
+            verify_index(registry, "", None)?;
+
+            // Without an index every subdirectory with a template.json is a template
+            let mut repos = Vec::new();
+            for entry in fs::read_dir(&dir)? {
+                let path = entry?.path();
+                if path.join("template.json").is_file() {
+                    let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+                    repos.push(Repo::new(&name, RepoSource::Path(path)));
+                }
+            }
+            repos.sort_by(|a, b| a.name.cmp(&b.name));
+            Ok(repos)
+        }
+    }
+}
+
+pub fn add_registry(name: &str, url: &str, priority: i32, keys: &[String]) -> anyhow::Result<()> {
+    if name.is_empty() || name.contains('/') {
+        return Err(anyhow!("Invalid registry name '{name}'"));
+    }
+    for key in keys {
+        parse_key(key)?;
+    }
+
+    // Local registries are stored with absolute paths so they work from any directory
+    let url = if is_url(url) {
+        url.to_string()
+    } else {
+        absolute_path(url)?.to_string_lossy().into_owned()
+    };
+
+    let mut config = Config::load()?;
+    config.registries.retain(|r| r.name != name);
+    config.registries.push(Registry { name: name.to_string(), url: url.clone(), priority, keys: keys.to_vec() });
+    config.save()?;
+
+    println!("Registry '{name}' -> {url} (priority {priority})");
+    Ok(())
+}
+
+pub fn remove_registry(name: &str) -> anyhow::Result<()> {
+    let mut config = Config::load()?;
+    let before = config.registries.len();
+    config.registries.retain(|r| r.name != name);
+
+    if config.registries.len() == before {
+        if name == DEFAULT_REGISTRY {
+            return Err(anyhow!("The built-in '{name}' registry cannot be removed, but it can be overridden with `kff registry add {name} <url>`"));
+        }
+        return Err(anyhow!("Unknown registry '{name}'"));
+    }
+
+    config.save()?;
+    println!("Registry '{name}' removed");
+    Ok(())
+}
+
+pub fn list_registries() -> anyhow::Result<()> {
+    for registry in Config::load()?.registries() {
+        let signed = if registry.keys.is_empty() { "" } else { " (signed)" };
+        println!("{:>4}  {:<16} {}{signed}", registry.priority, registry.name, registry.url);
+    }
+    Ok(())
+}
+
+/// Splits `template@ref` into its parts
+pub fn split_ref(name: &str) -> (&str, Option<&str>) {
+    match name.rsplit_once('@') {
+        // The `@` of `git@host:repo` and `https://user@host/repo` is not a ref
+        Some((name, git_ref))
+            if !name.is_empty()
+                && !git_ref.is_empty()
+                && !git_ref.contains(':')
+                && name.split_once("://").is_none_or(|(_, rest)| rest.contains('/')) =>
+        {
+            (name, Some(git_ref))
+        }
+        _ => (name, None),
+    }
+}
+
+/// Splits `registry/template` into its parts
+pub fn split_name(name: &str) -> (Option<&str>, &str) {
+    match name.split_once('/') {
+        Some((registry, template)) => (Some(registry), template),
+        None => (None, name),
+    }
+}
+
+/// Fetches `origin` unless offline, so new tags and branch heads are found, and
+/// returns the commit `git_ref` names
+fn fetch_ref(repo: &Repository, path: &Path, git_ref: &str) -> anyhow::Result<git2::Oid> {
+    if !is_offline()
+        && let Ok(mut remote) = repo.find_remote("origin")
+    {
+        remote
+            .fetch(&["+refs/heads/*:refs/remotes/origin/*", "+refs/tags/*:refs/tags/*"], None, None)
+            .with_context(|| format!("Failed to fetch '{}'", path.display()))?;
+    }
+
+    // Branches only exist as remote-tracking refs in a fresh clone
+    let object = repo
+        .revparse_single(&format!("origin/{git_ref}"))
+        .or_else(|_| repo.revparse_single(git_ref))
+        .with_context(|| format!("Unknown ref '{git_ref}'"))?;
+    Ok(object.peel_to_commit()?.id())
+}
+
+fn checkout_detached(repo: &Repository, commit: git2::Oid) -> anyhow::Result<()> {
+    let commit = repo.find_commit(commit)?;
+    repo.checkout_tree(commit.as_object(), Some(git2::build::CheckoutBuilder::new().force()))?;
+    repo.set_head_detached(commit.id())?;
+    Ok(())
+}
+
+/// Checks out a branch, tag or commit in a git checkout and returns the commit id
+pub fn checkout_ref(path: &Path, git_ref: &str) -> anyhow::Result<String> {
+    let repo = Repository::open(path)
+        .with_context(|| format!("'{}' is not a git repository, cannot check out '{git_ref}'", path.display()))?;
+    let commit = fetch_ref(&repo, path, git_ref)?;
+    checkout_detached(&repo, commit)?;
+    Ok(commit.to_string())
+}
+
+/// Checks out `git_ref` of a downloaded template in a copy at `dest`, so the downloaded
+/// template stays on its branch. Returns the commit id.
+pub fn checkout_copy(path: &Path, git_ref: &str, dest: &Path) -> anyhow::Result<String> {
+    let repo = Repository::open(path)
+        .with_context(|| format!("'{}' is not a git repository, cannot check out '{git_ref}'", path.display()))?;
+    let commit = fetch_ref(&repo, path, git_ref)?;
+
+    if dest.exists() {
+        fs::remove_dir_all(dest)?;
+    }
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut options = CopyOptions::new();
+    options.copy_inside = true;
+    copy(path, dest, &options).with_context(|| format!("Failed to copy '{}'", path.display()))?;
+
+    checkout_detached(&Repository::open(dest)?, commit)?;
+    Ok(commit.to_string())
+}
+
+/// Commit `git_ref` (or HEAD) points to in a downloaded template, without checking it out
+pub fn resolve_commit(path: &Path, git_ref: Option<&str>) -> anyhow::Result<String> {
+    let repo = Repository::open(path)?;
+    let object = match git_ref {
+        Some(git_ref) => repo
+            .revparse_single(&format!("origin/{git_ref}"))
+            .or_else(|_| repo.revparse_single(git_ref))
+            .with_context(|| format!("Unknown ref '{git_ref}'"))?,
+        None => repo.revparse_single("HEAD")?,
+    };
+    Ok(object.peel_to_commit()?.id().to_string())
+}
+
+/// Refuses a template whose commit differs from the one its registry entry pins
+pub fn verify_pin(repo: &Repo, commit: Option<&str>) -> anyhow::Result<()> {
+    let Some(pin) = &repo.commit else {
+        return Ok(());
+    };
+    let registry = match &repo.registry {
+        Some(name) => format!("registry '{name}'"),
+        None => "its registry".to_string(),
+    };
+    match commit {
+        Some(commit) if pin.len() >= 7 && commit.starts_with(&pin.to_lowercase()) => Ok(()),
+        Some(commit) => ensure_trusted(format!(
+            "Template '{}' is at commit {commit}, but {registry} pins {pin}",
+            repo.name
+        )),
+        None => ensure_trusted(format!(
+            "Template '{}' is not a git checkout, so it cannot be checked against the pinned commit {pin}",
+            repo.name
+        )),
+    }
+}
+
+/// Commit checked out in a downloaded template
+pub fn head_commit(path: &Path) -> Option<String> {
+    let repo = Repository::open(path).ok()?;
+    let commit = repo.head().ok()?.peel_to_commit().ok()?;
+    Some(commit.id().to_string())
+}
+
+/// Commit the remote's default branch points to (like `git ls-remote <url> HEAD`)
+pub fn remote_head(url: &str) -> anyhow::Result<String> {
+    ensure_online("query remote templates")?;
+    let mut remote = git2::Remote::create_detached(url)?;
+    remote.connect(git2::Direction::Fetch)?;
+    let head = remote
+        .list()?
+        .iter()
+        .find(|head| head.name() == "HEAD")
+        .map(|head| head.oid().to_string())
+        .ok_or_else(|| anyhow!("'{url}' has no HEAD"));
+    remote.disconnect()?;
+    head
+}
+
+/// URL of the `origin` remote of a downloaded template
+pub fn origin_url(path: &Path) -> Option<String> {
+    let repo = Repository::open(path).ok()?;
+    let remote = repo.find_remote("origin").ok()?;
+    remote.url().map(str::to_string)
+}
+
+/// Asks whether to use an already downloaded template. Without a terminal to ask on,
+/// the downloaded copy is used.
+pub fn choose_local_or_download(repo: &Repo, interactive: bool) -> anyhow::Result<bool> {
     if let RepoSource::Local = repo.url {
+        if !interactive {
+            return Ok(true);
+        }
         loop {
             println!("Local template '{}' found. Use it? (y/n)", repo.name);
             print!("> ");
             io::stdout().flush()?;
 
             let mut input = String::new();
-            io::stdin().read_line(&mut input)?;
+            if io::stdin().read_line(&mut input)? == 0 {
+                return Err(anyhow!("No answer given: standard input is closed"));
+            }
             let input = input.trim().to_lowercase();
 
             match input.as_str() {
@@ -49,23 +752,50 @@ pub fn choose_local_or_download(repo: &Repo) -> anyhow::Result<bool> {
     Ok(false)
 }
 
-pub fn search(template_name: &str) -> anyhow::Result<Repo> {
-    let response = reqwest::blocking::get(REPOSITORY)?;
-    let data = response.text()?;
+/// Looks a template up by `name` or `registry/name`, trying registries in precedence order
+pub fn search(name: &str) -> anyhow::Result<Repo> {
+    search_in(&Config::load()?.registries(), name)
+}
+
+fn search_in(registries: &[Registry], name: &str) -> anyhow::Result<Repo> {
+    let (registry_name, template_name) = split_name(name);
 
-    let repos: Vec<Repo> = serde_json::from_str(&data)?;
+    if let Some(registry_name) = registry_name
+        && !registries.iter().any(|r| r.name == registry_name)
+    {
+        return Err(anyhow!("Unknown registry '{registry_name}'"));
+    }
 
-    for repo in repos {
-        if repo.name == template_name {
-            return Ok(repo);
+    let mut failures = 0;
+    for registry in registries
+        .iter()
+        .filter(|r| registry_name.is_none_or(|name| r.name == name))
+    {
+        match index(registry) {
+            Ok(repos) => {
+                if let Some(mut repo) = repos.into_iter().find(|repo| repo.name == template_name) {
+                    repo.registry = Some(registry.name.clone());
+                    return Ok(repo);
+                }
+            }
+            Err(e) => {
+                eprintln!("WARNING: registry '{}' is unavailable: {e:#}", registry.name);
+                failures += 1;
+            }
         }
     }
 
-    Err(anyhow::anyhow!("Template {template_name} not found in repository"))
+    if failures == 0 {
+        Err(anyhow!("Template {template_name} not found in any registry"))
+    } else {
+        Err(anyhow!("Template {template_name} not found ({failures} registry(s) unavailable)"))
+    }
 }
 
 
-pub fn download(repo: &Repo) -> anyhow::Result<()> {
+/// Clones a template into `TEMPLATES_DIR`. Unless the user picked `git_ref`, the registry's
+/// pinned commit must be in the clone.
+pub fn download(repo: &Repo, git_ref: Option<&str>) -> anyhow::Result<()> {
     let destination_path = TEMPLATES_DIR.join(&repo.name);
 
     let RepoSource::Remote(url) = &repo.url else {
@@ -75,11 +805,235 @@ pub fn download(repo: &Repo) -> anyhow::Result<()> {
         ));
     };
 
-    if destination_path.exists() {
-        std::fs::remove_dir_all(&destination_path)?;
+    ensure_online(&format!("download template '{}'", repo.name))?;
+    clone_into(url, &destination_path)?;
+    println!("Cloned '{}' into {:?}", url, destination_path);
+
+    if git_ref.is_none() && repo.commit.is_some() {
+        let verified = resolve_commit(&destination_path, repo.default_ref())
+            .and_then(|commit| verify_pin(repo, Some(&commit)));
+        if let Err(e) = verified {
+            fs::remove_dir_all(&destination_path)?;
+            return Err(e);
+        }
     }
+    Ok(())
+}
 
-    Repository::clone(url, &destination_path)?;
-    println!("Cloned '{}' into {:?}", url, destination_path);
+/// Clones `url` into `dest`, replacing whatever is there
+pub fn clone_into(url: &str, dest: &Path) -> anyhow::Result<()> {
+    ensure_online(&format!("clone '{url}'"))?;
+
+    if dest.exists() {
+        std::fs::remove_dir_all(dest)?;
+    }
+
+    Repository::clone(url, dest).with_context(|| format!("Failed to clone '{url}'"))?;
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+    use tempfile::TempDir;
+    use crate::config::INSECURE;
+
+    fn registry(name: &str, url: &str, priority: i32) -> Registry {
+        Registry { name: name.to_string(), url: url.to_string(), priority, keys: Vec::new() }
+    }
+
+    fn add_template(dir: &Path, name: &str) {
+        fs::create_dir_all(dir.join(name)).unwrap();
+        fs::write(dir.join(name).join("template.json"), "{}").unwrap();
+    }
+
+    fn path_of(repo: &Repo) -> &Path {
+        match &repo.url {
+            RepoSource::Path(path) => path,
+            other => panic!("expected a local path, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn index_of_template_directory() {
+        let dir = TempDir::new().unwrap();
+        add_template(dir.path(), "beta");
+        add_template(dir.path(), "alpha");
+        fs::create_dir(dir.path().join("not-a-template")).unwrap();
+
+        let repos = index(&registry("local", &dir.path().to_string_lossy(), 0)).unwrap();
+        let names: Vec<&str> = repos.iter().map(|repo| repo.name.as_str()).collect();
+        assert_eq!(names, ["alpha", "beta"]);
+        assert_eq!(path_of(&repos[0]), dir.path().join("alpha"));
+    }
+
+    #[test]
+    fn index_file_resolves_relative_urls() {
+        let dir = TempDir::new().unwrap();
+        fs::write(
+            dir.path().join("templates.json"),
+            r#"[
+                {"name": "local", "url": "./templates/local"},
+                {"name": "remote", "url": "https://example.com/remote.git", "ref": "v1"}
+            ]"#,
+        )
+        .unwrap();
+        let expected = dir.path().join("templates").join("local");
+
+        // The directory, the index file itself and a file:// URL all find the same index
+        let index_file = dir.path().join("templates.json");
+        for url in [
+            dir.path().to_string_lossy().into_owned(),
+            index_file.to_string_lossy().into_owned(),
+            format!("file://{}", index_file.display()),
+        ] {
+            let repos = index(&registry("local", &url, 0)).unwrap();
+            assert_eq!(repos.len(), 2, "{url}");
+            assert_eq!(path_of(&repos[0]), expected, "{url}");
+            assert!(matches!(&repos[1].url, RepoSource::Remote(url) if url == "https://example.com/remote.git"));
+            assert_eq!(repos[1].git_ref.as_deref(), Some("v1"));
+        }
+    }
+
+    #[test]
+    fn index_of_missing_path_fails() {
+        let dir = TempDir::new().unwrap();
+        let missing = dir.path().join("missing");
+        assert!(index(&registry("gone", &missing.to_string_lossy(), 0)).is_err());
+    }
+
+    #[test]
+    fn registries_are_ordered_by_priority_then_name() {
+        let config = Config {
+            registries: vec![
+                registry("b", "/b", 5),
+                registry("a", "/a", 5),
+                registry("low", "/low", -1),
+                registry("top", "/top", 10),
+            ],
+            ..Default::default()
+        };
+        let names: Vec<String> = config.registries().into_iter().map(|r| r.name).collect();
+        assert_eq!(names, ["top", "a", "b", DEFAULT_REGISTRY, "low"]);
+
+        // A registry with the built-in name replaces it
+        let config = Config { registries: vec![registry(DEFAULT_REGISTRY, "/mirror", 0)], ..Default::default() };
+        let registries = config.registries();
+        assert_eq!(registries.len(), 1);
+        assert_eq!(registries[0].url, "/mirror");
+    }
+
+    #[test]
+    fn split_registry_and_template_names() {
+        assert_eq!(split_name("tpl"), (None, "tpl"));
+        assert_eq!(split_name("reg/tpl"), (Some("reg"), "tpl"));
+        assert_eq!(split_ref("reg/tpl@v1"), ("reg/tpl", Some("v1")));
+        assert_eq!(split_ref("git@github.com:user/tpl.git"), ("git@github.com:user/tpl.git", None));
+    }
+
+    #[test]
+    fn search_follows_precedence_and_registry_prefix() {
+        let high = TempDir::new().unwrap();
+        let low = TempDir::new().unwrap();
+        add_template(high.path(), "shared");
+        add_template(low.path(), "shared");
+        add_template(low.path(), "only-low");
+        let registries = [
+            registry("high", &high.path().to_string_lossy(), 10),
+            registry("low", &low.path().to_string_lossy(), 0),
+        ];
+
+        let repo = search_in(&registries, "shared").unwrap();
+        assert_eq!(repo.registry.as_deref(), Some("high"));
+        assert_eq!(path_of(&repo), high.path().join("shared"));
+
+        let repo = search_in(&registries, "low/shared").unwrap();
+        assert_eq!(repo.registry.as_deref(), Some("low"));
+        assert_eq!(path_of(&repo), low.path().join("shared"));
+
+        assert_eq!(search_in(&registries, "only-low").unwrap().registry.as_deref(), Some("low"));
+        assert!(search_in(&registries, "high/only-low").is_err());
+        assert!(search_in(&registries, "missing").is_err());
+        let err = search_in(&registries, "other/shared").unwrap_err();
+        assert_eq!(err.to_string(), "Unknown registry 'other'");
+    }
+
+    // Test keys and signatures of INDEX, made with minisign's format from fixed ed25519 seeds
+    const INDEX: &str = r#"[{"name":"tpl","url":"https://example.com/tpl.git"}]"#;
+    const KEY: &str = "RWQBAQEBAQEBAYqI4910CfGV/VLbLTy6XXLKZwm/HZQSG/N0iAG0D29c";
+    const OTHER_KEY: &str = "RWQCAgICAgICAoE5dw6ofRdfVqNUZsNMfszLjYqRtO43ol32D1uPybOU";
+    const SIGNATURE: &str = "untrusted comment: signature
+RUQBAQEBAQEBAbgDQsTGJ4xiTj43ceUTUgApYaM05R9Huhfi8Dvhj3ZYeF6gdqadWUo6w+24ftxuRacib5YaabpmEWfnxomYKQc=
+trusted comment: timestamp:0	file:templates.json
+OEtgwFKPxFrbAaf4mgMsHSCfj9jeKLcvdNMV+fFHKByEvOX37MsaybFaKEAWf5tL9l33cVUCbN1BOi7wtkjZBQ==
+";
+    const OTHER_SIGNATURE: &str = "untrusted comment: signature
+RUQCAgICAgICAsI29jDopWTJ0m4ZoTx9Ka95YjeISQ+G/fwTY6yWdFTKmcWwCatqH/hjg1uLu+DSXP/LZN3MHSDxn7QgsfitkgM=
+trusted comment: timestamp:0	file:templates.json
+u3kJUxNofJynm7ApNs7++8lCMgRRsq+hdQ3DdNN+BVHDVR8eWWqss/HoE9oB9xW+gwMs5k0jHNvVGhGL0RuzBg==
+";
+    const COMMIT: &str = "6dd2d56ce87a5c4326970d71d4046c6623baf79e";
+
+    fn signed_registry(keys: &[&str]) -> Registry {
+        Registry { keys: keys.iter().map(|key| key.to_string()).collect(), ..registry("signed", "/signed", 0) }
+    }
+
+    fn pinned(pin: &str) -> Repo {
+        let source = RepoSource::Remote("https://example.com/tpl.git".to_string());
+        Repo { commit: Some(pin.to_string()), ..Repo::new("tpl", source) }
+    }
+
+    #[test]
+    fn valid_signatures_and_pins_pass() {
+        assert!(verify_index(&signed_registry(&[KEY]), INDEX, Some(SIGNATURE)).is_ok());
+        // Any of the trusted keys will do
+        assert!(verify_index(&signed_registry(&[OTHER_KEY, KEY]), INDEX, Some(SIGNATURE)).is_ok());
+        // Registries without keys are not checked
+        assert!(verify_index(&registry("plain", "/plain", 0), INDEX, None).is_ok());
+
+        for pin in [COMMIT, &COMMIT[..7], &COMMIT[..12].to_uppercase()] {
+            assert!(verify_pin(&pinned(pin), Some(COMMIT)).is_ok(), "{pin}");
+        }
+        let unpinned = Repo::new("tpl", RepoSource::Local);
+        assert!(verify_pin(&unpinned, None).is_ok());
+    }
+
+    #[test]
+    fn signed_local_index_is_read_with_its_signature() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("templates.json"), INDEX).unwrap();
+        fs::write(dir.path().join("templates.json.minisig"), SIGNATURE).unwrap();
+        let registry = Registry { url: dir.path().to_string_lossy().into_owned(), ..signed_registry(&[KEY]) };
+        let repos = index(&registry).unwrap();
+        assert_eq!(repos[0].name, "tpl");
+    }
+
+    // Refusals and their `--insecure` warnings share one test, as INSECURE is process-wide
+    #[test]
+    fn untrusted_indexes_and_commits_are_refused_unless_insecure() {
+        let registry = signed_registry(&[KEY]);
+        let tampered = INDEX.replace("example.com", "example.org");
+        let checks = || {
+            [
+                ("unsigned index", verify_index(&registry, INDEX, None)),
+                ("signed by another key", verify_index(&registry, INDEX, Some(OTHER_SIGNATURE))),
+                ("modified index", verify_index(&registry, &tampered, Some(SIGNATURE))),
+                ("garbled signature", verify_index(&registry, INDEX, Some("not a signature"))),
+                ("other commit", verify_pin(&pinned(COMMIT), Some("09c99a6d6f0e1f7e0f4a3c1d9b8e7f6a5b4c3d2e"))),
+                ("pin too short", verify_pin(&pinned(&COMMIT[..4]), Some(COMMIT))),
+                ("not a checkout", verify_pin(&pinned(COMMIT), None)),
+            ]
+        };
+
+        for (case, result) in checks() {
+            assert!(result.is_err(), "{case} was accepted");
+        }
+        INSECURE.store(true, Ordering::Relaxed);
+        let insecure = checks();
+        INSECURE.store(false, Ordering::Relaxed);
+        for (case, result) in insecure {
+            assert!(result.is_ok(), "{case} was refused with --insecure");
+        }
+    }
+}
@@ -1,83 +1,759 @@
-use std::collections::HashMap;
-use std::{fs, io};
+use std::collections::{BTreeMap, HashMap};
+use std::{fmt, fs, io};
 use std::io::Write;
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
+use anyhow::{anyhow, Context};
+use glob::Pattern;
+use heck::{ToKebabCase, ToLowerCamelCase, ToSnakeCase, ToUpperCamelCase};
+use minijinja::Environment;
+use regex::Regex;
+use semver::Version;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::config::HOME;
+
+/// Answers keyed by question name
+pub type Answers = HashMap<String, Value>;
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Template {
-    questions: Vec<Question>,
-    files: Vec<File>,
+    /// Template this one is layered over: a template name, git URL or path relative to this template
+    #[serde(default)]
+    pub(crate) extends: Option<String>,
+    /// Directory of snippets inserted with `{{> name }}`; not copied into the project
+    #[serde(default)]
+    pub(crate) partials: Option<String>,
+    #[serde(default)]
+    pub(crate) engine: Engine,
+    #[serde(default)]
+    pub(crate) questions: Vec<Question>,
+    #[serde(default)]
+    pub(crate) files: Vec<File>,
+    /// Glob patterns that are never copied into the project
+    #[serde(default)]
+    pub(crate) ignore: Vec<String>,
+    /// Glob pattern -> expression; matching paths are kept only if the expression is true
+    #[serde(default)]
+    pub(crate) include_if: BTreeMap<String, String>,
+    /// Glob pattern -> expression; matching paths are dropped if the expression is true
+    #[serde(default)]
+    pub(crate) exclude_if: BTreeMap<String, String>,
+    /// Commands to run in the generated project, with the user's consent
+    #[serde(default)]
+    pub(crate) post_generate: Vec<Hook>,
+    /// Question whose answer names the output directory (`app_name` by default)
+    #[serde(default)]
+    output_key: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Hook {
+    pub(crate) command: String,
+    #[serde(default)]
+    pub(crate) args: Vec<String>,
+    /// Working directory relative to the generated project
+    #[serde(default)]
+    pub(crate) cwd: Option<String>,
+}
+
+/// What [`Template::render`] did to the template copy
+#[derive(Debug, Default)]
+pub struct RenderReport {
+    /// Placeholder replacements (placeholder engine)
+    pub(crate) substitutions: Vec<Substitution>,
+    /// Rendered `*.tmpl` files (jinja engine)
+    pub(crate) rendered: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct Substitution {
+    pub(crate) file: String,
+    pub(crate) placeholder: String,
+    /// `None` if the referenced question has no answer
+    pub(crate) value: Option<String>,
+    /// Occurrences of the placeholder in the file
+    pub(crate) count: usize,
+}
+
+/// How template files are turned into project files
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Engine {
+    /// Literal placeholder replacement in the files listed under `files`
+    #[default]
+    Placeholder,
+    /// Every `*.tmpl` file is rendered with minijinja and saved without the suffix
+    Jinja,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
-struct Question {
-    name: String,
+pub(crate) struct Question {
+    pub(crate) name: String,
     prompt: String,
     #[serde(rename = "type")]
-    qtype: String, // type is a reserved word in Rust
-    default: serde_json::Value,
+    qtype: QuestionType, // type is a reserved word in Rust
+    /// May contain `{{ ... }}` referring to earlier answers
+    #[serde(default)]
+    pub(crate) default: Value,
+    /// Expression over earlier answers; the question is skipped unless it is true
+    #[serde(default)]
+    pub(crate) when: Option<String>,
+    /// Allowed values for `choice` and `multiselect`
+    #[serde(default)]
+    choices: Vec<String>,
+    /// Regex the whole `string` answer must match
+    #[serde(default)]
+    pattern: Option<String>,
+    /// Message shown when `pattern` does not match
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default)]
+    min: Option<i64>,
+    #[serde(default)]
+    max: Option<i64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum QuestionType {
+    String,
+    Number,
+    Bool,
+    Choice,
+    Multiselect,
+    Path,
+    Semver,
+}
+
+impl fmt::Display for QuestionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            QuestionType::String => "string",
+            QuestionType::Number => "number",
+            QuestionType::Bool => "bool",
+            QuestionType::Choice => "choice",
+            QuestionType::Multiselect => "multiselect",
+            QuestionType::Path => "path",
+            QuestionType::Semver => "semver",
+        };
+        f.write_str(name)
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
-struct File {
-    file: String,
-    placeholders: Vec<Placeholder>,
+pub(crate) struct File {
+    pub(crate) file: String,
+    pub(crate) placeholders: Vec<Placeholder>,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
-struct Placeholder {
-    placeholder: String,
-    replacement: String,
+pub(crate) struct Placeholder {
+    pub(crate) placeholder: String,
+    pub(crate) replacement: String,
     description: String,
 }
 
+impl Placeholder {
+    /// Name of the question whose answer replaces this placeholder
+    pub(crate) fn question(&self) -> &str {
+        self.replacement.trim_matches('{').trim_matches('}')
+    }
+}
+
+/// Renders an answer the way it is written into template files
+pub fn value_to_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        _ => value.to_string(),
+    }
+}
+
+/// Creates the jinja environment with kff's extra filters
+pub fn environment() -> Environment<'static> {
+    let mut env = Environment::new();
+    env.set_keep_trailing_newline(true);
+    env.add_filter("snake_case", |value: &str| value.to_snake_case());
+    env.add_filter("pascal_case", |value: &str| value.to_upper_camel_case());
+    env.add_filter("camel_case", |value: &str| value.to_lower_camel_case());
+    env.add_filter("kebab_case", |value: &str| value.to_kebab_case());
+    env
+}
+
+/// Recursively lists all files under `root`, skipping `.git`
+pub fn walk_files(root: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.file_name().is_some_and(|name| name == ".git") {
+                continue;
+            }
+            if path.is_dir() {
+                pending.push(path);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+/// Layers the `template.json` of a template over the one of the template it extends.
+/// Questions and `files` entries with the same name replace the base's, lists are
+/// concatenated, conditions merged, and any other field set by `child` wins.
+pub fn merge_definitions(base: Value, child: Value) -> Value {
+    let (mut merged, child) = match (base, child) {
+        (Value::Object(base), Value::Object(child)) => (base, child),
+        (_, child) => return child,
+    };
+
+    for (key, value) in child {
+        let value = match (key.as_str(), merged.remove(&key), value) {
+            ("questions", Some(Value::Array(base)), Value::Array(child)) => Value::Array(merge_by(base, child, "name")),
+            ("files", Some(Value::Array(base)), Value::Array(child)) => Value::Array(merge_by(base, child, "file")),
+            ("ignore" | "post_generate", Some(Value::Array(mut base)), Value::Array(child)) => {
+                base.extend(child);
+                Value::Array(base)
+            }
+            ("include_if" | "exclude_if", Some(Value::Object(mut base)), Value::Object(child)) => {
+                base.extend(child);
+                Value::Object(base)
+            }
+            (_, _, value) => value,
+        };
+        merged.insert(key, value);
+    }
+    merged.remove("extends");
+    Value::Object(merged)
+}
+
+/// Replaces the items of `base` with the same `key` as an item of `child`, appending the others
+fn merge_by(mut base: Vec<Value>, child: Vec<Value>, key: &str) -> Vec<Value> {
+    for item in child {
+        match base.iter_mut().find(|existing| existing.get(key).is_some() && existing.get(key) == item.get(key)) {
+            Some(existing) => *existing = item,
+            None => base.push(item),
+        }
+    }
+    base
+}
+
+/// Replaces every `{{> name }}` in `text` with the snippet `dir/name`, recursively
+pub fn expand_partials(text: &str, dir: &Path) -> anyhow::Result<String> {
+    expand_partials_at(text, dir, 0)
+}
+
+fn expand_partials_at(text: &str, dir: &Path, depth: usize) -> anyhow::Result<String> {
+    let re = Regex::new(r"\{\{>\s*([^\s}]+)\s*\}\}")?;
+    if !re.is_match(text) {
+        return Ok(text.to_string());
+    }
+    if depth >= 8 {
+        return Err(anyhow!("Partials are nested too deeply (does one include itself?)"));
+    }
+
+    let mut expanded = String::new();
+    let mut last = 0;
+    for caps in re.captures_iter(text) {
+        let (whole, name) = (caps.get(0).unwrap(), &caps[1]);
+        if !Path::new(name).components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(anyhow!("Invalid partial name '{name}'"));
+        }
+        let snippet = fs::read_to_string(dir.join(name)).with_context(|| format!("Unknown partial '{name}'"))?;
+        // The include usually sits on its own line, which already ends with a newline
+        let snippet = snippet.strip_suffix('\n').unwrap_or(&snippet);
+
+        expanded.push_str(&text[last..whole.start()]);
+        expanded.push_str(&expand_partials_at(snippet, dir, depth + 1)?);
+        last = whole.end();
+    }
+    expanded.push_str(&text[last..]);
+    Ok(expanded)
+}
+
+/// Loads answers from a `.json` or `.toml` file
+pub fn load_answers<P: AsRef<Path>>(path: P) -> anyhow::Result<Answers> {
+    let path = path.as_ref();
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read answers file '{}'", path.display()))?;
+
+    let answers = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => {
+            let table: toml::Table = toml::from_str(&content)
+                .with_context(|| format!("Failed to parse answers file '{}'", path.display()))?;
+            serde_json::from_value(serde_json::to_value(table)?)?
+        }
+        _ => serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse answers file '{}'", path.display()))?,
+    };
+
+    Ok(answers)
+}
+
+/// Parses a `--set name=value` override
+pub fn parse_set(arg: &str) -> anyhow::Result<(String, Value)> {
+    let (name, value) = arg
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Invalid --set '{arg}', expected NAME=VALUE"))?;
+
+    Ok((name.trim().to_string(), Value::String(value.to_string())))
+}
+
 impl Template {
+    pub fn output_key(&self) -> &str {
+        self.output_key.as_deref().unwrap_or("app_name")
+    }
+
     pub fn parse_from_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
         let content = fs::read_to_string(path)?;
         let parsed: Template = serde_json::from_str(&content)?;
+        for q in &parsed.questions {
+            q.check_definition()?;
+        }
         Ok(parsed)
     }
+    /// Writes the answers into the template copy at `root` using the configured engine
+    pub fn render(&self, answers: &Answers, root: &Path) -> anyhow::Result<RenderReport> {
+        if let Some(partials) = &self.partials {
+            Self::include_partials(root, partials)?;
+        }
+
+        let mut report = RenderReport::default();
+        match self.engine {
+            Engine::Placeholder => report.substitutions = self.apply_replacements(answers, root)?,
+            Engine::Jinja => report.rendered = Self::render_tmpl_files(answers, root)?,
+        }
+
+        let env = environment();
+        let excluded = self.excluded_patterns(&env, answers)?;
+        Self::prune(&excluded, root, root)?;
+        Self::render_paths(&env, answers, root)?;
+        Ok(report)
+    }
+
+    /// Returns the `post_generate` hooks with `{{ ... }}` in their arguments rendered.
+    /// Besides the answers, `env` holds the environment variables (e.g. `{{ env.KSDK }}`).
+    pub fn hooks(&self, answers: &Answers) -> anyhow::Result<Vec<Hook>> {
+        let env = environment();
+        let vars: BTreeMap<String, String> = std::env::vars().collect();
+        let ctx = minijinja::context! { env => vars, ..minijinja::Value::from_serialize(answers) };
+        let render = |s: &str| {
+            env.render_str(s, &ctx)
+                .with_context(|| format!("Failed to render hook argument '{s}'"))
+        };
+
+        self.post_generate
+            .iter()
+            .map(|hook| {
+                Ok(Hook {
+                    command: render(&hook.command)?,
+                    args: hook.args.iter().map(|arg| render(arg)).collect::<anyhow::Result<_>>()?,
+                    cwd: hook.cwd.as_deref().map(render).transpose()?,
+                })
+            })
+            .collect()
+    }
+
+    /// Expands `{{> name }}` in every text file and removes the partials directory
+    fn include_partials(root: &Path, partials: &str) -> anyhow::Result<()> {
+        if !Path::new(partials).components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(anyhow!("Invalid partials directory '{partials}'"));
+        }
+        let dir = root.join(partials);
+
+        for path in walk_files(root)? {
+            if path.starts_with(&dir) {
+                continue;
+            }
+            let Ok(text) = fs::read_to_string(&path) else {
+                continue;
+            };
+            if text.contains("{{>") {
+                let name = path.strip_prefix(root)?.display().to_string();
+                let expanded = expand_partials(&text, &dir).with_context(|| format!("Failed to expand partials in '{name}'"))?;
+                fs::write(&path, expanded)?;
+            }
+        }
+
+        if dir.is_dir() {
+            fs::remove_dir_all(&dir)?;
+        }
+        Ok(())
+    }
+
+    /// Collects the `ignore` patterns plus every conditional pattern that applies to `answers`
+    fn excluded_patterns(&self, env: &Environment, answers: &Answers) -> anyhow::Result<Vec<Pattern>> {
+        let eval = |expr: &str| -> anyhow::Result<bool> {
+            let value = env
+                .compile_expression(expr)
+                .and_then(|e| e.eval(answers))
+                .with_context(|| format!("Failed to evaluate condition '{expr}'"))?;
+            Ok(value.is_true())
+        };
+
+        let mut patterns = Vec::new();
+        for pattern in &self.ignore {
+            patterns.push(pattern.as_str());
+        }
+        for (pattern, expr) in &self.include_if {
+            if !eval(expr)? {
+                patterns.push(pattern);
+            }
+        }
+        for (pattern, expr) in &self.exclude_if {
+            if eval(expr)? {
+                patterns.push(pattern);
+            }
+        }
+
+        patterns
+            .into_iter()
+            .map(|p| Pattern::new(p.trim_end_matches('/')).with_context(|| format!("Invalid pattern '{p}'")))
+            .collect()
+    }
+
+    /// Removes every file or directory under `dir` whose path relative to `root` matches a pattern
+    fn prune(patterns: &[Pattern], root: &Path, dir: &Path) -> anyhow::Result<()> {
+        if patterns.is_empty() {
+            return Ok(());
+        }
+
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.file_name().is_some_and(|name| name == ".git") {
+                continue;
+            }
+
+            let relative = path.strip_prefix(root)?.to_string_lossy().replace('\\', "/");
+            if patterns.iter().any(|p| p.matches(&relative)) {
+                if path.is_dir() {
+                    fs::remove_dir_all(&path)?;
+                } else {
+                    fs::remove_file(&path)?;
+                }
+            } else if path.is_dir() {
+                Self::prune(patterns, root, &path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Renames files and directories whose names contain `{{ ... }}`, deepest first
+    fn render_paths(env: &Environment, answers: &Answers, dir: &Path) -> anyhow::Result<()> {
+        let entries = fs::read_dir(dir)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for path in entries {
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            if name == ".git" {
+                continue;
+            }
+            if path.is_dir() {
+                Self::render_paths(env, answers, &path)?;
+            }
+            if !name.contains("{{") && !name.contains("{%") {
+                continue;
+            }
+
+            let rendered = env
+                .render_str(name, answers)
+                .with_context(|| format!("Failed to render path '{}'", path.display()))?;
+            let rendered = rendered.trim();
+            if rendered.is_empty() || rendered == "." || rendered == ".." || rendered.contains(['/', '\\']) {
+                return Err(anyhow!("Path '{}' renders to invalid name '{rendered}'", path.display()));
+            }
+
+            let target = path.with_file_name(rendered);
+            if target.exists() {
+                return Err(anyhow!("Path '{}' renders to '{}', which already exists", path.display(), target.display()));
+            }
+            fs::rename(&path, &target)?;
+        }
+        Ok(())
+    }
+
+    /// Renders every `*.tmpl` file and returns their names
+    fn render_tmpl_files(answers: &Answers, root: &Path) -> anyhow::Result<Vec<String>> {
+        let env = environment();
+        let mut rendered_files = Vec::new();
+
+        for path in walk_files(root)? {
+            if path.extension().is_none_or(|ext| ext != "tmpl") {
+                continue;
+            }
+
+            let name = path.strip_prefix(root)?.to_string_lossy().into_owned();
+            let source = fs::read_to_string(&path)?;
+            let rendered = env
+                .render_named_str(&name, &source, answers)
+                .with_context(|| format!("Failed to render '{name}'"))?;
+
+            fs::write(path.with_extension(""), rendered)?;
+            fs::remove_file(&path)?;
+            rendered_files.push(name);
+        }
+        Ok(rendered_files)
+    }
+
     pub fn apply_replacements(
         &self,
-        answers: &HashMap<String, String>,
+        answers: &Answers,
         root: &Path,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<Vec<Substitution>> {
+        let mut substitutions = Vec::new();
+
         for file_entry in &self.files {
             let file_path = root.join(&file_entry.file);
             let mut contents = fs::read_to_string(&file_path)?;
 
             for placeholder in &file_entry.placeholders {
-                if let Some(value) = answers.get(&placeholder.replacement.trim_matches('{').trim_matches('}').to_string()) {
+                let value = answers.get(placeholder.question()).map(value_to_string);
+                let count = contents.matches(&placeholder.placeholder).count();
+                if let Some(value) = &value {
                     contents = contents.replace(&placeholder.placeholder, value);
                 }
+                substitutions.push(Substitution {
+                    file: file_entry.file.clone(),
+                    placeholder: placeholder.placeholder.clone(),
+                    value,
+                    count,
+                });
             }
 
             fs::write(&file_path, contents)?;
         }
-        Ok(())
+        Ok(substitutions)
     }
-    pub fn ask_questions(&self) -> HashMap<String, String> {
-        let mut answers = HashMap::new();
+
+    /// Resolves an answer for every question.
+    ///
+    /// Questions are handled in order, so `when` conditions and templated defaults
+    /// can use earlier answers. `provided` answers take precedence, then defaults
+    /// (if `use_defaults`), then interactive prompts (if `interactive`). Anything
+    /// left over, as well as every answer that fails its type check, is reported
+    /// in a single error.
+    pub fn collect_answers(
+        &self,
+        provided: &Answers,
+        use_defaults: bool,
+        interactive: bool,
+    ) -> anyhow::Result<Answers> {
+        let env = environment();
+        let mut answers = Answers::new();
+        let mut problems = Vec::new();
 
         for q in &self.questions {
-            let value = Self::validate(&q.prompt, &q.qtype, q.default.clone());
-            answers.insert(q.name.clone(), value);
+            if let Some(when) = &q.when {
+                let asked = env
+                    .compile_expression(when)
+                    .and_then(|expr| expr.eval(&answers))
+                    .map(|value| value.is_true());
+                match asked {
+                    Ok(true) => {}
+                    Ok(false) => continue,
+                    Err(e) => {
+                        problems.push(format!("'{}': invalid condition '{}': {}", q.name, when, e));
+                        continue;
+                    }
+                }
+            }
+
+            let default = match q.resolve_default(&env, &answers) {
+                Ok(default) => default,
+                Err(e) => {
+                    problems.push(format!("'{}': invalid default: {}", q.name, e));
+                    continue;
+                }
+            };
+
+            if let Some(value) = provided.get(&q.name) {
+                match q.check(value) {
+                    Ok(value) => {
+                        answers.insert(q.name.clone(), value);
+                    }
+                    Err(e) => problems.push(format!("'{}': {}", q.name, e)),
+                }
+            } else if use_defaults && !default.is_null() {
+                match q.check(&default) {
+                    Ok(value) => {
+                        answers.insert(q.name.clone(), value);
+                    }
+                    Err(e) => problems.push(format!("'{}': invalid default: {}", q.name, e)),
+                }
+            } else if interactive {
+                let value = q.ask(&default);
+                answers.insert(q.name.clone(), value);
+            } else {
+                problems.push(format!("'{}': missing answer", q.name));
+            }
+        }
+
+        for name in provided.keys() {
+            if !self.questions.iter().any(|q| &q.name == name) {
+                eprintln!("WARNING: answer '{name}' does not match any question");
+            }
+        }
+
+        if !problems.is_empty() {
+            return Err(anyhow!(
+                "Missing or invalid answers:\n  - {}",
+                problems.join("\n  - ")
+            ));
+        }
+
+        Ok(answers)
+    }
+}
+
+impl Question {
+    /// Rejects question definitions that can never be answered
+    fn check_definition(&self) -> anyhow::Result<()> {
+        let is_choice = matches!(self.qtype, QuestionType::Choice | QuestionType::Multiselect);
+        if is_choice && self.choices.is_empty() {
+            return Err(anyhow!("Question '{}' has no choices", self.name));
+        }
+        if !is_choice && !self.choices.is_empty() {
+            return Err(anyhow!("Question '{}' has choices but is not a choice or multiselect", self.name));
+        }
+        if let Some(pattern) = &self.pattern {
+            if self.qtype != QuestionType::String {
+                return Err(anyhow!("Question '{}' has a pattern but is not a string", self.name));
+            }
+            Regex::new(pattern).with_context(|| format!("Question '{}' has an invalid pattern", self.name))?;
+        }
+        if (self.min.is_some() || self.max.is_some()) && self.qtype != QuestionType::Number {
+            return Err(anyhow!("Question '{}' has min/max but is not a number", self.name));
+        }
+        Ok(())
+    }
+
+    /// Type-checks a non-interactive answer the same way as typed input
+    fn check(&self, value: &Value) -> Result<Value, String> {
+        match value {
+            Value::String(_) | Value::Number(_) | Value::Bool(_) => {
+                self.parse_input(&value_to_string(value))
+            }
+            Value::Array(items) if self.qtype == QuestionType::Multiselect => items
+                .iter()
+                .map(|item| match item {
+                    Value::String(s) => self.parse_choice(s),
+                    _ => Err(format!("expected a list of strings, got {value}")),
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map(Value::from),
+            _ => Err(format!("expected a {} answer, got {value}", self.qtype)),
+        }
+    }
+
+    fn parse_input(&self, input: &str) -> Result<Value, String> {
+        match self.qtype {
+            QuestionType::String => {
+                if let Some(pattern) = &self.pattern {
+                    let re = Regex::new(&format!("^(?:{pattern})$")).map_err(|e| e.to_string())?;
+                    if !re.is_match(input) {
+                        return Err(self.error.clone().unwrap_or_else(|| {
+                            format!("'{input}' does not match the pattern '{pattern}'.")
+                        }));
+                    }
+                }
+                Ok(Value::String(input.to_string()))
+            }
+
+            QuestionType::Number => {
+                let number = input
+                    .parse::<i64>()
+                    .map_err(|_| format!("Invalid number '{input}', please enter a valid integer."))?;
+                if self.min.is_some_and(|min| number < min) || self.max.is_some_and(|max| number > max) {
+                    return Err(format!(
+                        "Number {number} is out of range ({}..{}).",
+                        self.min.map(|n| n.to_string()).unwrap_or_default(),
+                        self.max.map(|n| n.to_string()).unwrap_or_default(),
+                    ));
+                }
+                Ok(Value::from(number))
+            }
+
+            QuestionType::Bool => match input.to_lowercase().as_str() {
+                "true" | "1" | "yes" | "y" => Ok(Value::Bool(true)),
+                "false" | "0" | "no" | "n" => Ok(Value::Bool(false)),
+                _ => Err(format!("Invalid boolean '{input}', please enter yes/no, true/false, 1/0.")),
+            },
+
+            QuestionType::Choice => self.parse_choice(input).map(Value::String),
+
+            QuestionType::Multiselect => input
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(|item| self.parse_choice(item))
+                .collect::<Result<Vec<_>, _>>()
+                .map(Value::from),
+
+            QuestionType::Path => {
+                let path = match input.strip_prefix("~/") {
+                    Some(rest) => HOME.join(rest),
+                    None => PathBuf::from(input),
+                };
+                if !path.exists() {
+                    return Err(format!("Path '{}' does not exist.", path.display()));
+                }
+                Ok(Value::String(path.to_string_lossy().into_owned()))
+            }
+
+            QuestionType::Semver => Version::parse(input)
+                .map(|version| Value::String(version.to_string()))
+                .map_err(|e| format!("Invalid version '{input}': {e}.")),
         }
+    }
 
-        answers
+    /// Accepts either a choice itself or its 1-based number in the list
+    fn parse_choice(&self, input: &str) -> Result<String, String> {
+        if let Some(choice) = self.choices.iter().find(|c| c.as_str() == input) {
+            return Ok(choice.clone());
+        }
+        if let Some(choice) = input.parse::<usize>().ok().and_then(|n| self.choices.get(n.wrapping_sub(1))) {
+            return Ok(choice.clone());
+        }
+        Err(format!("Invalid choice '{input}', expected one of: {}.", self.choices.join(", ")))
     }
 
-    fn validate(prompt: &str, qtype: &str, default: serde_json::Value) -> String {
-        fn default_to_string(default: &serde_json::Value) -> String {
-            match default {
-                serde_json::Value::String(s) => s.clone(),
-                _ => default.to_string(),
+    /// Renders a string default containing `{{ ... }}` against the answers so far
+    fn resolve_default(&self, env: &Environment, answers: &Answers) -> Result<Value, minijinja::Error> {
+        match &self.default {
+            Value::String(s) if s.contains("{{") || s.contains("{%") => {
+                env.render_str(s, answers).map(Value::String)
             }
+            value => Ok(value.clone()),
         }
+    }
+
+    fn ask(&self, default: &Value) -> Value {
+        if !self.choices.is_empty() {
+            println!("{}:", self.prompt);
+            for (i, choice) in self.choices.iter().enumerate() {
+                println!("  {}) {}", i + 1, choice);
+            }
+        }
+
+        let hint = match self.qtype {
+            QuestionType::Multiselect => " (comma separated)",
+            _ => "",
+        };
+        let shown = match default {
+            Value::Null => String::new(),
+            Value::Array(items) => format!(" [{}]", items.iter().map(value_to_string).collect::<Vec<_>>().join(", ")),
+            value => format!(" [{}]", value_to_string(value)),
+        };
 
         loop {
-            print!("{} [{}]: ", prompt, default_to_string(&default));
+            print!("{}{}{}: ", self.prompt, hint, shown);
             io::stdout().flush().unwrap();
 
             let mut input = String::new();
@@ -85,34 +761,175 @@ impl Template {
             let trimmed = input.trim();
 
             // If input is empty, use default
-            if trimmed.is_empty() {
-                return default_to_string(&default);
+            if trimmed.is_empty() && !default.is_null() {
+                match self.check(default) {
+                    Ok(value) => return value,
+                    Err(e) => {
+                        println!("{e}");
+                        continue;
+                    }
+                }
             }
 
-            match qtype {
-                "string" => return trimmed.to_string(),
+            match self.parse_input(trimmed) {
+                Ok(value) => return value,
+                Err(e) => println!("{e}"),
+            }
+        }
+    }
+}
 
-                "number" => {
-                    if trimmed.parse::<i64>().is_ok() {
-                        return trimmed.to_string();
-                    } else {
-                        println!("Invalid number, please enter a valid integer.");
-                    }
-                }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
 
-                "bool" => {
-                    match trimmed.to_lowercase().as_str() {
-                        "true" | "1" | "yes" | "y" => return "true".to_string(),
-                        "false" | "0" | "no" | "n" => return "false".to_string(),
-                        _ => println!("Invalid boolean, please enter yes/no, true/false, 1/0."),
-                    }
-                }
+    fn question(mut definition: Value) -> Question {
+        definition["name"] = json!("q");
+        definition["prompt"] = json!("Q");
+        serde_json::from_value(definition).unwrap()
+    }
 
-                _ => {
-                    println!("Unknown type '{}', treating input as string.", qtype);
-                    return trimmed.to_string();
-                }
+    /// Runs `(input, expected)` cases, `None` meaning the input must be rejected
+    fn check_inputs(definition: Value, cases: &[(&str, Option<Value>)]) {
+        let question = question(definition);
+        question.check_definition().unwrap();
+        for (input, expected) in cases {
+            let parsed = question.parse_input(input);
+            match expected {
+                Some(expected) => assert_eq!(parsed.as_ref().ok(), Some(expected), "input {input:?}"),
+                None => assert!(parsed.is_err(), "input {input:?} was accepted as {parsed:?}"),
             }
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn choice_by_value_or_number() {
+        check_inputs(
+            json!({ "type": "choice", "choices": ["meson", "cmake"] }),
+            &[
+                ("meson", Some(json!("meson"))),
+                ("cmake", Some(json!("cmake"))),
+                ("1", Some(json!("meson"))),
+                ("2", Some(json!("cmake"))),
+                // 0 must not wrap around to the last choice
+                ("0", None),
+                ("3", None),
+                ("make", None),
+                ("", None),
+            ],
+        );
+    }
+
+    #[test]
+    fn numbered_choice_prefers_the_value() {
+        check_inputs(json!({ "type": "choice", "choices": ["2", "1"] }), &[("1", Some(json!("1"))), ("3", None)]);
+    }
+
+    #[test]
+    fn multiselect_is_comma_separated() {
+        check_inputs(
+            json!({ "type": "multiselect", "choices": ["gtk2", "kual", "meson"] }),
+            &[
+                ("gtk2, meson", Some(json!(["gtk2", "meson"]))),
+                ("2,1", Some(json!(["kual", "gtk2"]))),
+                ("kual,,", Some(json!(["kual"]))),
+                ("", Some(json!([]))),
+                ("gtk2, qt", None),
+            ],
+        );
+    }
+
+    #[test]
+    fn multiselect_accepts_lists() {
+        let question = question(json!({ "type": "multiselect", "choices": ["a", "b"] }));
+        assert_eq!(question.check(&json!(["b", "1"])), Ok(json!(["b", "a"])));
+        assert!(question.check(&json!(["c"])).is_err());
+        assert!(question.check(&json!([1])).is_err());
+    }
+
+    #[test]
+    fn number_range() {
+        check_inputs(
+            json!({ "type": "number", "min": 1, "max": 65535 }),
+            &[
+                ("1", Some(json!(1))),
+                ("8080", Some(json!(8080))),
+                ("65535", Some(json!(65535))),
+                ("0", None),
+                ("65536", None),
+                ("-5", None),
+                ("12.5", None),
+                ("port", None),
+            ],
+        );
+        check_inputs(json!({ "type": "number" }), &[("-5", Some(json!(-5)))]);
+    }
+
+    #[test]
+    fn pattern_matches_the_whole_answer() {
+        check_inputs(
+            json!({ "type": "string", "pattern": "[a-z_]+" }),
+            &[
+                ("my_app", Some(json!("my_app"))),
+                ("My_app", None),
+                ("my app", None),
+                ("app1", None),
+            ],
+        );
+        check_inputs(json!({ "type": "string", "pattern": "a|b" }), &[("a", Some(json!("a"))), ("ab", None)]);
+    }
+
+    #[test]
+    fn pattern_uses_the_custom_error() {
+        let question = question(json!({ "type": "string", "pattern": "[a-z]+", "error": "lowercase only" }));
+        assert_eq!(question.parse_input("ABC"), Err("lowercase only".to_string()));
+    }
+
+    #[test]
+    fn bool_and_semver() {
+        check_inputs(
+            json!({ "type": "bool" }),
+            &[("yes", Some(json!(true))), ("N", Some(json!(false))), ("0", Some(json!(false))), ("maybe", None)],
+        );
+        check_inputs(
+            json!({ "type": "semver" }),
+            &[
+                ("1.2.3", Some(json!("1.2.3"))),
+                ("1.0.0-beta.1", Some(json!("1.0.0-beta.1"))),
+                ("1.2", None),
+                ("v1.2.3", None),
+            ],
+        );
+    }
+
+    #[test]
+    fn invalid_definitions_are_rejected() {
+        let invalid = [
+            json!({ "type": "choice" }),
+            json!({ "type": "multiselect", "choices": [] }),
+            json!({ "type": "string", "choices": ["a"] }),
+            json!({ "type": "number", "pattern": "[0-9]+" }),
+            json!({ "type": "string", "pattern": "(" }),
+            json!({ "type": "string", "min": 1 }),
+            json!({ "type": "bool", "max": 1 }),
+        ];
+        for definition in invalid {
+            assert!(question(definition.clone()).check_definition().is_err(), "{definition} was accepted");
+        }
+    }
+
+    #[test]
+    fn unknown_types_fail_to_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("template.json");
+        fs::write(&path, r#"{ "questions": [{ "name": "q", "prompt": "Q", "type": "color" }] }"#).unwrap();
+        assert!(Template::parse_from_file(&path).is_err());
+
+        fs::write(&path, r#"{ "questions": [{ "name": "q", "prompt": "Q", "type": "choice" }] }"#).unwrap();
+        assert!(Template::parse_from_file(&path).is_err());
+
+        fs::write(&path, r#"{ "questions": [{ "name": "q", "prompt": "Q", "type": "semver" }] }"#).unwrap();
+        assert!(Template::parse_from_file(&path).is_ok());
+    }
+}
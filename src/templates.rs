@@ -0,0 +1,177 @@
+use std::fs;
+use std::path::Path;
+use anyhow::{anyhow, Context, Result};
+use fs_extra::dir::{copy, CopyOptions};
+use git2::build::CheckoutBuilder;
+use git2::Repository;
+use crate::catalog::short;
+use crate::config::{ensure_online, STAGING_DIR, TEMPLATES_DIR};
+use crate::repository::{self, RepoSource};
+
+/// Copies a local template into `dest`; git checkouts are cloned so they can be updated later
+fn copy_local(path: &Path, dest: &Path) -> Result<()> {
+    if path.join(".git").exists() {
+        Repository::clone(&path.to_string_lossy(), dest)
+            .with_context(|| format!("Failed to clone '{}'", path.display()))?;
+        return Ok(());
+    }
+
+    fs::create_dir_all(&*TEMPLATES_DIR)?;
+    let mut options = CopyOptions::new();
+    options.copy_inside = true;
+    copy(path, dest, &options)?;
+    Ok(())
+}
+
+/// Downloads a template by registry name, git URL or local path into `TEMPLATES_DIR`
+pub fn install(source: &str, git_ref: Option<&str>, force: bool) -> Result<()> {
+    let (repo, name_ref) = match repository::from_source(source)? {
+        Some(repo) => (repo, None),
+        None => {
+            let (name, name_ref) = repository::split_ref(source);
+            (repository::search(name)?, name_ref)
+        }
+    };
+    let chosen_ref = git_ref.or(name_ref);
+    let git_ref = chosen_ref.or(repo.default_ref());
+
+    let dest = TEMPLATES_DIR.join(&repo.name);
+    if dest.exists() {
+        if !force {
+            return Err(anyhow!(
+                "Template '{}' is already installed; use `kff template update {}` or --force to reinstall it",
+                repo.name,
+                repo.name
+            ));
+        }
+        fs::remove_dir_all(&dest)?;
+    }
+
+    match &repo.url {
+        RepoSource::Remote(_) => repository::download(&repo, chosen_ref)?,
+        RepoSource::Path(path) => copy_local(path, &dest)?,
+        RepoSource::Local => return Err(anyhow!("Template '{}' has no source to install from", repo.name)),
+    }
+
+    if let Some(git_ref) = git_ref {
+        let commit = repository::checkout_ref(&dest, git_ref)?;
+        println!("Checked out '{git_ref}' ({commit})");
+    }
+    println!("Installed template '{}' into {}", repo.name, dest.display());
+    Ok(())
+}
+
+/// Fetches `origin` and fast-forwards the checked out branch, returning what happened
+fn fast_forward(path: &Path) -> Result<String> {
+    let Ok(repo) = Repository::open(path) else {
+        return Ok("not a git checkout, skipped".to_string());
+    };
+    let Ok(mut remote) = repo.find_remote("origin") else {
+        return Ok("no origin remote, skipped".to_string());
+    };
+    remote.fetch(&["+refs/heads/*:refs/remotes/origin/*", "+refs/tags/*:refs/tags/*"], None, None)?;
+
+    let Ok(head) = repo.head() else {
+        return Ok("no commits, skipped".to_string());
+    };
+    let current = head.peel_to_commit()?.id();
+    if !head.is_branch() {
+        return Ok(format!("pinned at {} (detached), left as is", short(&current.to_string())));
+    }
+    let head_name = head.name().ok_or_else(|| anyhow!("invalid branch name"))?.to_string();
+    let branch = head.shorthand().unwrap_or_default().to_string();
+
+    let upstream = repo
+        .find_reference(&format!("refs/remotes/origin/{branch}"))
+        .with_context(|| format!("branch '{branch}' does not exist on origin"))?;
+    let target = repo.reference_to_annotated_commit(&upstream)?;
+    let (analysis, _) = repo.merge_analysis(&[&target])?;
+    if analysis.is_up_to_date() {
+        return Ok("already up to date".to_string());
+    }
+    if !analysis.is_fast_forward() {
+        return Err(anyhow!("'{branch}' has local commits and cannot be fast-forwarded"));
+    }
+
+    let object = repo.find_object(target.id(), None)?;
+    repo.checkout_tree(&object, Some(CheckoutBuilder::new().safe()))
+        .context("local changes would be overwritten")?;
+    repo.find_reference(&head_name)?
+        .set_target(target.id(), "kff template update: fast-forward")?;
+    Ok(format!("{} -> {}", short(&current.to_string()), short(&target.id().to_string())))
+}
+
+/// Fast-forwards one or all downloaded templates to their upstream branch
+pub fn update(name: Option<&str>) -> Result<()> {
+    ensure_online("update templates")?;
+
+    if let Some(name) = name {
+        let path = TEMPLATES_DIR.join(name);
+        if !path.is_dir() {
+            return Err(anyhow!("Template '{name}' is not installed"));
+        }
+        let status = fast_forward(&path).with_context(|| format!("Failed to update '{name}'"))?;
+        println!("{name}: {status}");
+        return Ok(());
+    }
+
+    let mut names: Vec<String> = match fs::read_dir(&*TEMPLATES_DIR) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_dir())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    names.sort();
+    if names.is_empty() {
+        println!("No templates installed");
+        return Ok(());
+    }
+
+    let mut failures = 0;
+    for name in names {
+        match fast_forward(&TEMPLATES_DIR.join(&name)) {
+            Ok(status) => println!("{name}: {status}"),
+            Err(e) => {
+                eprintln!("WARNING: {name}: {e:#}");
+                failures += 1;
+            }
+        }
+    }
+    if failures > 0 {
+        return Err(anyhow!("{failures} template(s) could not be updated"));
+    }
+    Ok(())
+}
+
+/// Deletes a downloaded template
+pub fn remove(name: &str) -> Result<()> {
+    if name.is_empty() || name.contains(['/', '\\']) || name == "." || name == ".." {
+        return Err(anyhow!("Invalid template name '{name}'"));
+    }
+    let path = TEMPLATES_DIR.join(name);
+    if !path.is_dir() {
+        return Err(anyhow!("Template '{name}' is not installed"));
+    }
+    fs::remove_dir_all(&path).with_context(|| format!("Failed to remove '{}'", path.display()))?;
+    println!("Removed template '{name}'");
+    Ok(())
+}
+
+/// Deletes the staging directories left behind by `generate` and `update`
+pub fn prune() -> Result<()> {
+    if !STAGING_DIR.is_dir() {
+        println!("Nothing to prune");
+        return Ok(());
+    }
+    // Staging directories are grouped by what created them (`render`, `update`, ...)
+    let count: usize = fs::read_dir(&*STAGING_DIR)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| fs::read_dir(entry.path()).map_or(1, |dir| dir.count()))
+        .sum();
+    fs::remove_dir_all(&*STAGING_DIR)
+        .with_context(|| format!("Failed to remove '{}'", STAGING_DIR.display()))?;
+    println!("Removed {count} staging director(ies) from {}", STAGING_DIR.display());
+    Ok(())
+}
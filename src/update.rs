@@ -0,0 +1,368 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Context};
+use git2::Repository;
+use crate::cli::UpdateArgs;
+use crate::config::{self, STAGING_DIR, TEMPLATES_DIR};
+use crate::generator::{render_staged, stage, Bases};
+use crate::lock::{TemplateLock, LOCK_FILE};
+use crate::repository;
+use crate::template::{walk_files, Answers};
+
+/// What happened to a single project file
+#[derive(Debug, PartialEq)]
+enum Change {
+    Added,
+    Updated,
+    Merged,
+    Removed,
+    Conflict,
+    /// Binary file changed on both sides; the template's version was written next to it
+    ConflictCopy,
+    /// Removed from the template but modified locally
+    Kept,
+}
+
+impl Change {
+    fn label(&self) -> &'static str {
+        match self {
+            Change::Added => "added",
+            Change::Updated => "updated",
+            Change::Merged => "merged",
+            Change::Removed => "removed",
+            Change::Conflict | Change::ConflictCopy => "CONFLICT",
+            Change::Kept => "kept",
+        }
+    }
+}
+
+/// Relative paths of every rendered file, without the lock file
+fn rendered_files(root: &Path) -> anyhow::Result<BTreeSet<PathBuf>> {
+    Ok(walk_files(root)?
+        .into_iter()
+        .filter_map(|path| path.strip_prefix(root).ok().map(Path::to_path_buf))
+        .filter(|path| path != Path::new(LOCK_FILE))
+        .collect())
+}
+
+fn read(path: &Path) -> anyhow::Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read '{}'", path.display())),
+    }
+}
+
+fn write(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, data).with_context(|| format!("Failed to write '{}'", path.display()))
+}
+
+/// Three-way merge of one file: `base` is the old rendering, `theirs` the new one
+/// and `target` the user's copy in the project
+fn merge_file(base: Option<&[u8]>, theirs: Option<&[u8]>, target: &Path) -> anyhow::Result<Option<Change>> {
+    if base == theirs {
+        return Ok(None);
+    }
+    let ours = read(target)?;
+
+    let Some(theirs) = theirs else {
+        // Removed from the template
+        return match ours {
+            Some(ours) if Some(ours.as_slice()) == base => {
+                fs::remove_file(target)?;
+                Ok(Some(Change::Removed))
+            }
+            Some(_) => Ok(Some(Change::Kept)),
+            None => Ok(None),
+        };
+    };
+
+    let Some(ours) = ours else {
+        // Deleted by the user, who does not want it back
+        if base.is_some() {
+            return Ok(None);
+        }
+        write(target, theirs)?;
+        return Ok(Some(Change::Added));
+    };
+
+    if ours == theirs {
+        return Ok(None);
+    }
+    if Some(ours.as_slice()) == base {
+        write(target, theirs)?;
+        return Ok(Some(Change::Updated));
+    }
+
+    let texts = (
+        std::str::from_utf8(base.unwrap_or_default()),
+        std::str::from_utf8(&ours),
+        std::str::from_utf8(theirs),
+    );
+    match texts {
+        (Ok(base), Ok(ours), Ok(theirs)) => match diffy::merge(base, ours, theirs) {
+            Ok(merged) => {
+                write(target, merged.as_bytes())?;
+                Ok(Some(Change::Merged))
+            }
+            Err(conflicted) => {
+                write(target, conflicted.as_bytes())?;
+                Ok(Some(Change::Conflict))
+            }
+        },
+        _ => {
+            let mut copy = target.as_os_str().to_owned();
+            copy.push(".kff-new");
+            write(Path::new(&copy), theirs)?;
+            Ok(Some(Change::ConflictCopy))
+        }
+    }
+}
+
+/// Re-renders the template a project was generated from at a newer commit and
+/// merges the changes between the old and the new rendering into the project
+pub fn run(update_args: &UpdateArgs) -> anyhow::Result<()> {
+    let project = match &update_args.dir {
+        Some(dir) => dir.clone(),
+        None => std::env::current_dir()?,
+    };
+    let mut lock = TemplateLock::read(&project)?;
+    let base_commit = lock
+        .commit
+        .clone()
+        .ok_or_else(|| anyhow!("{LOCK_FILE} records no template commit, so the original rendering cannot be rebuilt"))?;
+
+    // Offline, the downloaded copy still has the full history
+    let local = TEMPLATES_DIR.join(&lock.template);
+    let source = match &lock.url {
+        Some(url) if !config::is_offline() => url.clone(),
+        _ if local.is_dir() => local.to_string_lossy().into_owned(),
+        _ => return Err(anyhow!("Template '{}' is not available offline", lock.template)),
+    };
+
+    let work = STAGING_DIR.join("update").join(&lock.template);
+    if work.exists() {
+        fs::remove_dir_all(&work)?;
+    }
+    let checkout = work.join("source");
+    println!("Fetching template '{}' from {}", lock.template, source);
+    Repository::clone(&source, &checkout).with_context(|| format!("Failed to clone '{source}'"))?;
+
+    // A registry that pins a commit only vouches for that one
+    let entry = match &lock.registry {
+        Some(registry) => match repository::search(&format!("{registry}/{}", lock.template)) {
+            Ok(repo) => Some(repo),
+            Err(e) => {
+                config::ensure_trusted(format!("Cannot check '{}' against its registry: {e:#}", lock.template))?;
+                None
+            }
+        },
+        None => None,
+    };
+    let registry_ref = entry.as_ref().and_then(|repo| repo.git_ref.as_deref());
+
+    // --ref beats the ref the project was generated from, which beats the registry's pin and then its ref.
+    // Like in `generate`, a ref picked by the user is not checked against the pin.
+    let chosen_ref = update_args
+        .git_ref
+        .as_deref()
+        .or(lock.git_ref.as_deref().filter(|git_ref| Some(*git_ref) != registry_ref));
+    let git_ref = chosen_ref.or(entry.as_ref().and_then(|repo| repo.default_ref())).unwrap_or("HEAD");
+    let new_commit = repository::checkout_ref(&checkout, git_ref)?;
+    if chosen_ref.is_none()
+        && let Some(repo) = &entry
+    {
+        repository::verify_pin(repo, Some(&new_commit))?;
+    }
+
+    // New rendering, with the current versions of the templates it extends
+    let new_dir = work.join("new");
+    let (new_template, new_bases) =
+        stage(&repository::template_dir(&checkout, lock.subdir.as_deref())?, &new_dir, Bases::Latest)?;
+    if new_commit == base_commit && new_bases == lock.bases {
+        println!("Project is already up to date with '{}' ({new_commit})", lock.template);
+        return Ok(());
+    }
+
+    // Old rendering, from the recorded commits and answers
+    repository::checkout_ref(&checkout, &base_commit)
+        .with_context(|| format!("The project's template commit is missing from {source}"))?;
+    let old_dir = work.join("old");
+    let (old_template, _) =
+        stage(&repository::template_dir(&checkout, lock.subdir.as_deref())?, &old_dir, Bases::Locked(&lock.bases))?;
+    let recorded: Answers = lock.answers.clone().into_iter().collect();
+    let old_answers = old_template
+        .collect_answers(&recorded, true, false)
+        .context("Failed to reproduce the original rendering")?;
+    render_staged(&old_template, &old_answers, &old_dir)?;
+
+    // Only questions the template did not ask before need answers
+    let interactive = !update_args.defaults && std::io::stdin().is_terminal();
+    let new_answers = new_template.collect_answers(&recorded, update_args.defaults, interactive)?;
+    render_staged(&new_template, &new_answers, &new_dir)?;
+
+    if new_commit == base_commit {
+        println!("Updating '{}' to new versions of the templates '{}' extends", project.display(), lock.template);
+    } else {
+        println!("Updating '{}' from {} to {}", project.display(), base_commit, new_commit);
+    }
+    let old_files = rendered_files(&old_dir)?;
+    let new_files = rendered_files(&new_dir)?;
+    let mut conflicts = 0;
+    for path in old_files.union(&new_files) {
+        let base = read(&old_dir.join(path))?;
+        let theirs = read(&new_dir.join(path))?;
+        let Some(change) = merge_file(base.as_deref(), theirs.as_deref(), &project.join(path))? else {
+            continue;
+        };
+        match change {
+            Change::Conflict => conflicts += 1,
+            Change::ConflictCopy => {
+                conflicts += 1;
+                println!("  {:<9}{} (template version in {}.kff-new)", change.label(), path.display(), path.display());
+                continue;
+            }
+            Change::Kept => {
+                println!("  {:<9}{} (removed from the template, but modified locally)", change.label(), path.display());
+                continue;
+            }
+            _ => {}
+        }
+        println!("  {:<9}{}", change.label(), path.display());
+    }
+
+    lock.commit = Some(new_commit);
+    lock.bases = new_bases;
+    if let Some(git_ref) = &update_args.git_ref {
+        lock.git_ref = Some(git_ref.clone());
+    }
+    lock.answers = new_answers.into_iter().collect();
+    lock.write(&project)?;
+
+    if conflicts > 0 {
+        println!("WARNING: {conflicts} file(s) have conflicts; resolve the conflict markers before building");
+    } else {
+        println!("Project updated");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = "one\ntwo\nthree\nfour\nfive\n";
+
+    fn merge(base: Option<&str>, theirs: Option<&str>, ours: Option<&[u8]>) -> (Option<Change>, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("file.txt");
+        if let Some(ours) = ours {
+            fs::write(&target, ours).unwrap();
+        }
+        let change = merge_file(base.map(str::as_bytes), theirs.map(str::as_bytes), &target).unwrap();
+        (change, dir)
+    }
+
+    fn content(dir: &tempfile::TempDir) -> Option<String> {
+        fs::read_to_string(dir.path().join("file.txt")).ok()
+    }
+
+    #[test]
+    fn unchanged_template_leaves_file_alone() {
+        let (change, dir) = merge(Some(BASE), Some(BASE), Some(b"local edit\n"));
+        assert_eq!(change, None);
+        assert_eq!(content(&dir).as_deref(), Some("local edit\n"));
+    }
+
+    #[test]
+    fn removed_upstream_and_unmodified_is_removed() {
+        let (change, dir) = merge(Some(BASE), None, Some(BASE.as_bytes()));
+        assert_eq!(change, Some(Change::Removed));
+        assert_eq!(content(&dir), None);
+    }
+
+    #[test]
+    fn removed_upstream_but_modified_is_kept() {
+        let (change, dir) = merge(Some(BASE), None, Some(b"local edit\n"));
+        assert_eq!(change, Some(Change::Kept));
+        assert_eq!(content(&dir).as_deref(), Some("local edit\n"));
+    }
+
+    #[test]
+    fn removed_on_both_sides_is_nothing() {
+        let (change, dir) = merge(Some(BASE), None, None);
+        assert_eq!(change, None);
+        assert_eq!(content(&dir), None);
+    }
+
+    #[test]
+    fn deleted_locally_stays_deleted() {
+        let (change, dir) = merge(Some(BASE), Some("changed\n"), None);
+        assert_eq!(change, None);
+        assert_eq!(content(&dir), None);
+    }
+
+    #[test]
+    fn added_upstream_is_written() {
+        let (change, dir) = merge(None, Some("new\n"), None);
+        assert_eq!(change, Some(Change::Added));
+        assert_eq!(content(&dir).as_deref(), Some("new\n"));
+    }
+
+    #[test]
+    fn same_change_on_both_sides_is_nothing() {
+        let (change, dir) = merge(Some(BASE), Some("same\n"), Some(b"same\n"));
+        assert_eq!(change, None);
+        assert_eq!(content(&dir).as_deref(), Some("same\n"));
+    }
+
+    #[test]
+    fn unmodified_file_is_fast_forwarded() {
+        let theirs = BASE.replace("three", "THREE");
+        let (change, dir) = merge(Some(BASE), Some(&theirs), Some(BASE.as_bytes()));
+        assert_eq!(change, Some(Change::Updated));
+        assert_eq!(content(&dir), Some(theirs));
+    }
+
+    #[test]
+    fn changes_on_different_lines_are_merged() {
+        let theirs = BASE.replace("five", "FIVE");
+        let ours = BASE.replace("one", "ONE");
+        let (change, dir) = merge(Some(BASE), Some(&theirs), Some(ours.as_bytes()));
+        assert_eq!(change, Some(Change::Merged));
+        assert_eq!(content(&dir).as_deref(), Some("ONE\ntwo\nthree\nfour\nFIVE\n"));
+    }
+
+    #[test]
+    fn changes_on_the_same_line_conflict() {
+        let theirs = BASE.replace("three", "theirs");
+        let ours = BASE.replace("three", "ours");
+        let (change, dir) = merge(Some(BASE), Some(&theirs), Some(ours.as_bytes()));
+        assert_eq!(change, Some(Change::Conflict));
+        let merged = content(&dir).unwrap();
+        assert!(merged.contains("<<<<<<<") && merged.contains("ours") && merged.contains("theirs"));
+    }
+
+    #[test]
+    fn added_on_both_sides_differently_conflicts() {
+        let (change, dir) = merge(None, Some("theirs\n"), Some(b"ours\n"));
+        assert_eq!(change, Some(Change::Conflict));
+        assert!(content(&dir).unwrap().contains("<<<<<<<"));
+    }
+
+    #[test]
+    fn binary_conflict_writes_a_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("image.png");
+        fs::write(&target, [0xff, 0x01]).unwrap();
+        let change = merge_file(Some(&[0xff, 0x00]), Some(&[0xff, 0x02]), &target).unwrap();
+        assert_eq!(change, Some(Change::ConflictCopy));
+        assert_eq!(fs::read(&target).unwrap(), [0xff, 0x01]);
+        assert_eq!(fs::read(dir.path().join("image.png.kff-new")).unwrap(), [0xff, 0x02]);
+    }
+}